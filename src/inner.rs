@@ -1,266 +1,2246 @@
-use std::alloc::{alloc, dealloc, realloc, Layout};
-use std::mem::ManuallyDrop;
+use std::alloc::{alloc, dealloc, handle_alloc_error, realloc, GlobalAlloc, Layout};
+use std::cell::{Cell, UnsafeCell};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::marker::PhantomData;
+use std::mem::{offset_of, ManuallyDrop};
+use std::num::NonZeroU8;
+use std::str::{from_utf8_unchecked, from_utf8_unchecked_mut};
+#[cfg(not(feature = "compact-header"))]
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{mem::size_of, ptr, slice, str::from_utf8_unchecked};
+use std::sync::OnceLock;
+use std::{cmp, fmt, mem::size_of, ptr, slice};
 
+#[cfg(not(feature = "compact-header"))]
 const fn max(a: usize, b: usize) -> usize {
     [a, b][(a < b) as usize]
 }
 
-const CACHE_LINE_SIZE: usize = max(64 /* maybe 128? */, size_of::<AtomicUsize>());
+#[cfg(not(feature = "compact-header"))]
+const CACHE_LINE_SIZE: usize = max(64 /* maybe 128? */, size_of::<Header<AtomicUsize>>());
 // TODO make power of two
 
-struct ArcVecU8 {
+// The header of the shared buffer is the strong and weak counters and the capacity.
+// By default it takes the whole cache line, so the counters of different buffers
+// don't share it and the text is aligned to it, the rest of the line caches the hash.
+// The compact header saves memory for many short shared strings,
+// but clone and drop of neighbour buffers from different threads contend (false sharing).
+#[cfg(not(feature = "compact-header"))]
+const HEADER_SIZE: usize = CACHE_LINE_SIZE;
+#[cfg(not(feature = "compact-header"))]
+const HEADER_ALIGN: usize = CACHE_LINE_SIZE;
+#[cfg(feature = "compact-header")]
+const HEADER_SIZE: usize = size_of::<Header<AtomicUsize>>();
+#[cfg(feature = "compact-header")]
+const HEADER_ALIGN: usize = std::mem::align_of::<Header<AtomicUsize>>();
+
+const _: () = [()][!(HEADER_ALIGN.is_power_of_two()) as usize];
+const _: () = [()][!HEADER_SIZE.is_multiple_of(HEADER_ALIGN) as usize];
+const _: () = [()][!(HEADER_SIZE >= size_of::<Header<AtomicUsize>>()) as usize];
+const _: () = [()][!(HEADER_ALIGN >= std::mem::align_of::<Header<AtomicUsize>>()) as usize];
+
+#[inline(always)]
+fn align_capacity(capacity: usize) -> usize {
+    capacity.div_ceil(HEADER_ALIGN) * HEADER_ALIGN
+}
+
+// The keys of hash_code are random per process, like in HashMap,
+// so the cached hash doesn't let anyone choose colliding strings
+fn hash_state() -> &'static RandomState {
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    STATE.get_or_init(RandomState::new)
+}
+
+/// Counter of the shared buffer: `AtomicUsize` for FastString, `Cell<usize>` for LocalFastString.
+pub trait RefCount: Counter {}
+
+impl RefCount for AtomicUsize {}
+impl RefCount for Cell<usize> {}
+
+// Not reexported, so RefCount can't be implemented outside of the crate
+pub trait Counter {
+    fn one() -> Self;
+
+    fn increment(&self);
+
+    // Returns true for the last owner, which frees the buffer.
+    // WEAK_MARK of the weak counter isn't counted.
+    fn decrement(&self) -> bool;
+
+    fn is_unique(&self) -> bool;
+
+    fn count(&self) -> usize;
+
+    // Increments the strong counter unless it's zero, for upgrade
+    fn increment_nonzero(&self) -> bool;
+
+    // The weak counter is locked while the strong counter is checked for get_mut,
+    // so no weak reference is upgraded in between, like in std::sync::Arc
+    fn lock_unique(&self) -> bool;
+
+    // Unlocks the weak counter without weak references, clearing WEAK_MARK
+    fn unlock(&self);
+
+    // Increments the weak counter and sets WEAK_MARK, waiting while it's locked
+    fn increment_marked(&self);
+}
+
+// Set in the weak counter by downgrade and cleared only by get_mut.
+// The unique owner which sees the weak counter equal to 1 after the strong one
+// skips the lock: every other owner has made its weak references before
+// its Release decrement, which the Acquire load of the strong counter synchronizes with.
+const WEAK_MARK: usize = 1 << (usize::BITS - 2);
+
+impl Counter for AtomicUsize {
+    #[inline(always)]
+    fn one() -> Self {
+        AtomicUsize::new(1)
+    }
+
+    #[inline(always)]
+    fn increment(&self) {
+        self.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn decrement(&self) -> bool {
+        if self.fetch_sub(1, Ordering::Release) & !WEAK_MARK != 1 {
+            return false;
+        }
+        std::sync::atomic::fence(Ordering::Acquire);
+        true
+    }
+
+    #[inline(always)]
+    fn is_unique(&self) -> bool {
+        self.load(Ordering::Acquire) == 1
+    }
+
+    #[inline(always)]
+    fn count(&self) -> usize {
+        self.load(Ordering::Acquire)
+    }
+
+    fn increment_nonzero(&self) -> bool {
+        self.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+            (count != 0).then_some(count + 1)
+        })
+        .is_ok()
+    }
+
+    #[inline(always)]
+    fn lock_unique(&self) -> bool {
+        let count = self.load(Ordering::Relaxed);
+        count & !WEAK_MARK == 1
+            && self
+                .compare_exchange(count, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    #[inline(always)]
+    fn unlock(&self) {
+        self.store(1, Ordering::Release);
+    }
+
+    fn increment_marked(&self) {
+        let mut count = self.load(Ordering::Relaxed);
+        loop {
+            if count == usize::MAX {
+                std::hint::spin_loop();
+                count = self.load(Ordering::Relaxed);
+                continue;
+            }
+            let marked = (count | WEAK_MARK) + 1;
+            match self.compare_exchange_weak(count, marked, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(old) => count = old,
+            }
+        }
+    }
+}
+
+impl Counter for Cell<usize> {
+    #[inline(always)]
+    fn one() -> Self {
+        Cell::new(1)
+    }
+
+    #[inline(always)]
+    fn increment(&self) {
+        self.set(self.get() + 1);
+    }
+
+    #[inline(always)]
+    fn decrement(&self) -> bool {
+        self.set(self.get() - 1);
+        self.get() & !WEAK_MARK == 0
+    }
+
+    #[inline(always)]
+    fn is_unique(&self) -> bool {
+        self.get() == 1
+    }
+
+    #[inline(always)]
+    fn count(&self) -> usize {
+        self.get()
+    }
+
+    fn increment_nonzero(&self) -> bool {
+        if self.get() == 0 {
+            return false;
+        }
+        self.increment();
+        true
+    }
+
+    #[inline(always)]
+    fn lock_unique(&self) -> bool {
+        self.get() & !WEAK_MARK == 1
+    }
+
+    #[inline(always)]
+    fn unlock(&self) {
+        self.set(1);
+    }
+
+    fn increment_marked(&self) {
+        self.set((self.get() | WEAK_MARK) + 1);
+    }
+}
+
+/// Allocator of the shared buffer, [Global] by default.
+/// Every string stores its own clone of the handle,
+/// so a zero-sized allocator doesn't grow the string.
+///
+/// # Safety
+///
+/// Memory returned by any clone of the handle can be deallocated or reallocated
+/// by any other clone, like by the handle that allocated it.
+pub unsafe trait Allocator: Clone {
+    /// Returns null if the allocation fails.
+    ///
+    /// # Safety
+    ///
+    /// The same as [GlobalAlloc::alloc].
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// The same as [GlobalAlloc::dealloc].
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout);
+
+    /// Returns null if the allocation fails, then the old memory is still valid.
+    ///
+    /// # Safety
+    ///
+    /// The same as [GlobalAlloc::realloc].
+    unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.allocate(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, std::cmp::min(layout.size(), new_size));
+            self.deallocate(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// The global allocator, registered with `#[global_allocator]` or the default one.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    #[inline(always)]
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc(ptr, layout, new_size)
+    }
+}
+
+// A reference to an arena or a tracking allocator
+unsafe impl<T: GlobalAlloc> Allocator for &T {
+    #[inline(always)]
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        (**self).realloc(ptr, layout, new_size)
+    }
+}
+
+// The header is reused in place when a unique string changes its counter
+const _: () = [()][!(size_of::<Cell<usize>>() == size_of::<AtomicUsize>()) as usize];
+
+// The strong owners together hold one weak reference, so the buffer is freed
+// when both counters drop to zero
+#[repr(C)]
+struct Header<R> {
+    counter: R,
+    weak: R,
+    capacity: usize,
+    // The hash of the first hash_len - 1 bytes: zero if it isn't computed,
+    // usize::MAX while it's written. It's written once until get_mut resets it.
+    #[cfg(not(feature = "compact-header"))]
+    hash_len: AtomicUsize,
+    #[cfg(not(feature = "compact-header"))]
+    hash: AtomicU64,
+}
+
+// Points to the start of the buffer, which is right after the header
+struct ArcVecU8<R: RefCount> {
+    data: *mut u8,
+    _counter: PhantomData<R>,
+}
+
+impl<R: RefCount> ArcVecU8<R> {
+    pub unsafe fn try_with_capacity<A: Allocator>(
+        capacity: usize,
+        alloc: &A,
+    ) -> Result<Self, TryReserveError> {
+        let layout = Layout::from_size_align_unchecked(HEADER_SIZE + capacity, HEADER_ALIGN);
+        let pointer = alloc.allocate(layout);
+        if pointer.is_null() {
+            return Err(TryReserveError::AllocError { layout });
+        }
+        ptr::write(
+            pointer as *mut Header<R>,
+            Header {
+                counter: R::one(),
+                weak: R::one(),
+                capacity,
+                #[cfg(not(feature = "compact-header"))]
+                hash_len: AtomicUsize::new(0),
+                #[cfg(not(feature = "compact-header"))]
+                hash: AtomicU64::new(0),
+            },
+        );
+        Ok(Self {
+            data: pointer.add(HEADER_SIZE),
+            _counter: PhantomData,
+        })
+    }
+
+    pub unsafe fn with_capacity<A: Allocator>(capacity: usize, alloc: &A) -> Self {
+        Self::try_with_capacity(capacity, alloc).unwrap_or_else(|error| error.handle())
+    }
+
+    #[inline(never)]
+    unsafe fn drop_slow<A: Allocator>(&mut self, alloc: &A) {
+        alloc.deallocate(
+            self.data.sub(HEADER_SIZE),
+            Layout::from_size_align_unchecked(HEADER_SIZE + self.capacity(), HEADER_ALIGN),
+        );
+    }
+
+    #[inline]
+    pub unsafe fn drop<A: Allocator>(&mut self, alloc: &A) {
+        if self.release() {
+            self.drop_weak(alloc);
+        }
+    }
+
+    // Releases the strong reference, the last one must free the text and call drop_weak
+    #[inline(always)]
+    pub unsafe fn release(&self) -> bool {
+        self.get_counter().decrement()
+    }
+
+    // Releases the weak reference, the text isn't accessed after it
+    pub unsafe fn drop_weak<A: Allocator>(&mut self, alloc: &A) {
+        if !self.header().weak.decrement() {
+            return;
+        }
+        self.drop_slow(alloc);
+    }
+
+    pub unsafe fn downgrade(&self) {
+        self.header().weak.increment_marked();
+    }
+
+    pub unsafe fn clone_weak(&self) {
+        self.header().weak.increment();
+    }
+
+    // Takes the strong reference by the weak one, if the text is still alive
+    pub unsafe fn upgrade(&self) -> bool {
+        self.get_counter().increment_nonzero()
+    }
+
+    // Neither other strings nor weak references to the buffer
+    pub unsafe fn is_unique(&self) -> bool {
+        let header = self.header();
+        if !header.counter.is_unique() {
+            return false;
+        }
+        // No downgrade since the last check, see WEAK_MARK
+        if header.weak.is_unique() {
+            return true;
+        }
+        if !header.weak.lock_unique() {
+            return false;
+        }
+        let unique = header.counter.is_unique();
+        header.weak.unlock();
+        unique
+    }
+
+    // Other strings or weak references, racy unlike is_unique
+    pub unsafe fn is_shared(&self) -> bool {
+        let header = self.header();
+        !header.counter.is_unique() || header.weak.count() & !WEAK_MARK != 1
+    }
+
+    // The text can be changed after it, so the hash is reset
+    pub unsafe fn get_mut(&mut self) -> Option<&mut ArcVecU8<R>> {
+        if self.is_unique() {
+            #[cfg(not(feature = "compact-header"))]
+            self.header().hash_len.store(0, Ordering::Relaxed);
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    // The hash of the first len bytes, computed once for all owners of the buffer
+    #[cfg(not(feature = "compact-header"))]
+    pub unsafe fn cached_hash(&self, len: usize, compute: impl FnOnce() -> u64) -> u64 {
+        let header = self.header();
+        // len + 1 so the hash of the empty text differs from no hash
+        let hash_len = header.hash_len.load(Ordering::Acquire);
+        if hash_len == len + 1 {
+            return header.hash.load(Ordering::Relaxed);
+        }
+        let hash = compute();
+        if hash_len == 0
+            && header
+                .hash_len
+                .compare_exchange(0, usize::MAX, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            header.hash.store(hash, Ordering::Relaxed);
+            header.hash_len.store(len + 1, Ordering::Release);
+        }
+        hash
+    }
+
+    // The compact header has no room for the hash
+    #[cfg(feature = "compact-header")]
+    pub unsafe fn cached_hash(&self, _len: usize, compute: impl FnOnce() -> u64) -> u64 {
+        compute()
+    }
+
+    // Replaces the counters of the unique buffer
+    pub unsafe fn into_counter<S: RefCount>(self) -> ArcVecU8<S> {
+        let header = self.data.sub(HEADER_SIZE) as *mut Header<S>;
+        ptr::write(ptr::addr_of_mut!((*header).counter), S::one());
+        ptr::write(ptr::addr_of_mut!((*header).weak), S::one());
+        ArcVecU8 {
+            data: self.data,
+            _counter: PhantomData,
+        }
+    }
+
+    // Grows or shrinks the buffer, the old buffer stays valid on failure
+    pub unsafe fn try_realloc<A: Allocator>(
+        &mut self,
+        new_capacity: usize,
+        alloc: &A,
+    ) -> Result<(), TryReserveError> {
+        // TODO realloc copies the whole capacity, if it's much bigger than the len,
+        //      alloc new_capacity and copy only the len would be better
+        let pointer = alloc.reallocate(
+            self.data.sub(HEADER_SIZE),
+            Layout::from_size_align_unchecked(HEADER_SIZE + self.capacity(), HEADER_ALIGN),
+            HEADER_SIZE + new_capacity,
+        );
+        if pointer.is_null() {
+            return Err(TryReserveError::AllocError {
+                layout: Layout::from_size_align_unchecked(HEADER_SIZE + new_capacity, HEADER_ALIGN),
+            });
+        }
+        self.data = pointer.add(HEADER_SIZE);
+        (*(pointer as *mut Header<R>)).capacity = new_capacity;
+        Ok(())
+    }
+
+    pub unsafe fn extend_from(&mut self, old_size: usize, bytes: *const u8, size: usize) {
+        ptr::copy_nonoverlapping(bytes, self.data.add(old_size), size);
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.data
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.data
+    }
+
+    #[inline(always)]
+    unsafe fn header(&self) -> &Header<R> {
+        &*(self.data.sub(HEADER_SIZE) as *const Header<R>)
+    }
+
+    #[inline(always)]
+    unsafe fn get_counter(&self) -> &R {
+        &self.header().counter
+    }
+
+    #[inline(always)]
+    unsafe fn capacity(&self) -> usize {
+        self.header().capacity
+    }
+}
+
+// The header of Adopted is the buffer with StringBuffer as its data.
+// Its pointer is stored shifted right by two with both kind bits, the header is aligned.
+impl<R: RefCount> ArcVecU8<R> {
+    unsafe fn with_string_buffer<A: Allocator>(buffer: StringBuffer, alloc: &A) -> Self {
+        let header = Self::with_capacity(size_of::<StringBuffer>(), alloc);
+        ptr::write(header.data as *mut StringBuffer, buffer);
+        header
+    }
+
+    #[inline(always)]
+    unsafe fn string_buffer(&self) -> &StringBuffer {
+        &*(self.data as *const StringBuffer)
+    }
+
+    #[inline(always)]
+    fn to_word(&self) -> usize {
+        self.data as usize >> 2 | KIND_MASK
+    }
+
+    // A copy of the pointer, which doesn't change the counters
+    #[inline(always)]
+    unsafe fn from_word(word: usize) -> Self {
+        Self {
+            data: ((word & !KIND_MASK) << 2) as *mut u8,
+            _counter: PhantomData,
+        }
+    }
+}
+
+impl<R: RefCount> Clone for ArcVecU8<R> {
+    fn clone(&self) -> Self {
+        unsafe {
+            self.get_counter().increment();
+        }
+        Self {
+            data: self.data,
+            _counter: PhantomData,
+        }
+    }
+}
+
+// The text is at the offset in the shared buffer, so substrings can share it
+#[repr(C)]
+struct Large<R: RefCount> {
+    data: ArcVecU8<R>,
+    offset: usize,
+    len: usize,
+}
+
+// Owns the buffer of an adopted String without refcount header.
+// Allocated with alignment 1, so it can be given back to String as is.
+// Must have data and len like Large, as_str reads them through Large.
+// The capacity is stored with MEDIUM_BIT.
+#[repr(C)]
+struct Medium {
+    data: *mut u8,
+    capacity: usize,
+    len: usize,
+}
+
+impl Medium {
+    #[inline(always)]
+    fn new(data: *mut u8, capacity: usize, len: usize) -> Self {
+        debug_assert!(capacity <= MAX_LEN);
+        Medium {
+            data,
+            capacity: capacity | MEDIUM_BIT,
+            len: pack_len(len),
+        }
+    }
+
+    #[inline(always)]
+    fn capacity(&self) -> usize {
+        self.capacity & !MEDIUM_BIT
+    }
+
+    #[inline(always)]
+    fn set_capacity(&mut self, capacity: usize) {
+        // Otherwise the capacity would overwrite the kind bits
+        debug_assert!(capacity <= MAX_LEN);
+        self.capacity = capacity | MEDIUM_BIT;
+    }
+}
+
+// Medium after its first clone, substring or weak reference: the String buffer is shared,
+// the counters are in the separate header, which stores the buffer.
+// The text is at data like in Static, so a substring just points to its start.
+// Must have the same layout as Large, the header word has both kind bits.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Adopted {
+    data: *const u8,
+    header: usize,
+    len: usize,
+}
+
+// The buffer of the adopted String, allocated with alignment 1 by the global allocator
+struct StringBuffer {
     data: *mut u8,
+    capacity: usize,
+}
+
+impl StringBuffer {
+    #[inline(always)]
+    unsafe fn dealloc(&self) {
+        dealloc(
+            self.data,
+            Layout::from_size_align_unchecked(self.capacity, 1),
+        );
+    }
+}
+
+// Points to a string with static lifetime, the buffer is never freed or written.
+// Must have the same layout as Large, as_str reads both through Large.
+// A substring just points to its start, the offset of Large is STATIC_BIT here.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Static {
+    data: *const u8,
+    kind: usize,
+    len: usize,
+}
+
+impl Static {
+    #[inline(always)]
+    fn new(data: *const u8, len: usize) -> Self {
+        Static {
+            data,
+            kind: STATIC_BIT,
+            len: pack_len(len),
+        }
+    }
+}
+
+// Large, Adopted or Static of the inline capacity of two words, which has no room
+// for the word before len. The kind is in two bits of len below the tag instead.
+// Large has no offset, so it always starts its buffer, and Adopted points to its header.
+// Their substrings share only the prefixes, the other ones are copied.
+// There is no Medium: the String buffer gets the header at once and is copied on mutation.
+#[repr(C)]
+struct Compact<T> {
+    data: T,
+    len: usize,
+}
+
+impl<T> Compact<T> {
+    #[inline(always)]
+    fn new(data: T, kind: usize, len: usize) -> Self {
+        Compact {
+            data,
+            len: pack_len(len | kind >> COMPACT_SHIFT),
+        }
+    }
+}
+
+// The last byte is the tag byte:
+//   0x01..=0xBF - small with N len, the byte is the last byte of the string,
+//                 valid UTF-8 never ends with 0xC0..=0xFF, zero is never used (niche)
+//   0xC0..=0xDD - small with len less than min(N, SMALL_SHORT), 0xC0 | len
+//   0xDE        - small with len from SMALL_SHORT to N - 2, the len is the byte before the tag
+//   0xDF        - small with N - 1 len, when it isn't less than SMALL_SHORT
+//   0xE0..=0xFF - medium, large, adopted or static, the five low bits are the high bits of len
+// Medium, Large, Adopted and Static are placed at the end of the inline buffer,
+// so len is the last word. Their kind is in the two high bits of the word before len,
+// which are never used otherwise: the offset of Large and the capacity of Medium don't exceed
+// MAX_LEN, the header of Adopted is shifted right. Large has no bits there, Medium has
+// MEDIUM_BIT, Static has STATIC_BIT and Adopted has both.
+// The inline capacity of two words has room only for Compact, see there.
+// Default inline capacity: 24 bytes on 64-bit targets, 12 bytes on 32-bit targets
+pub const SMALL_CAPACITY: usize = HEAP_SIZE;
+const HEAP_SIZE: usize = size_of::<Large<AtomicUsize>>();
+const SMALL_FLAG: u8 = 0xC0;
+const SMALL_SHORT: usize = 30;
+const SMALL_LONG_FLAG: u8 = 0xDE;
+const SMALL_LAST_FLAG: u8 = 0xDF;
+const HEAP_FLAG: u8 = 0xE0;
+const LARGE_SHIFT: usize = (size_of::<usize>() - 1) * 8;
+const LARGE_MASK: usize = !((HEAP_FLAG as usize) << LARGE_SHIFT);
+const MEDIUM_BIT: usize = 1 << (usize::BITS - 1);
+const STATIC_BIT: usize = 1 << (usize::BITS - 2);
+const KIND_MASK: usize = MEDIUM_BIT | STATIC_BIT;
+// Longer length would overwrite the tag: 512 MiB on 32-bit targets, 2 EiB on 64-bit targets.
+// The tag must not look like the small tags 0xC0..=0xDF, so 1 GiB on 32-bit targets
+// isn't possible since the inline string got the full capacity.
+// Compact takes two more bits of len for the kind, see StringInner::MAX_LEN.
+pub const MAX_LEN: usize = LARGE_MASK;
+const COMPACT_SHIFT: u32 = 3;
+
+#[cold]
+#[inline(never)]
+fn capacity_overflow() -> ! {
+    panic!("capacity overflow");
+}
+
+/// The error of the `try_*` methods, which return it instead of panic or abort.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TryReserveError {
+    /// The length would exceed `MAX_LEN`.
+    CapacityOverflow,
+    /// The allocator returned null for the layout.
+    AllocError { layout: Layout },
+}
+
+impl TryReserveError {
+    // The same as the infallible methods do
+    #[cold]
+    #[inline(never)]
+    fn handle(self) -> ! {
+        match self {
+            TryReserveError::CapacityOverflow => capacity_overflow(),
+            TryReserveError::AllocError { layout } => handle_alloc_error(layout),
+        }
+    }
+}
+
+impl fmt::Display for TryReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("memory allocation failed")?;
+        match self {
+            TryReserveError::CapacityOverflow => {
+                f.write_str(" because the computed capacity exceeded the maximum length")
+            }
+            TryReserveError::AllocError { .. } => {
+                f.write_str(" because the memory allocator returned an error")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TryReserveError {}
+
+// Len with the tag is stored in little endian on every target,
+// so the high byte with the tag is always the last byte of the struct
+#[inline(always)]
+fn pack_len(len: usize) -> usize {
+    (len | (HEAP_FLAG as usize) << LARGE_SHIFT).to_le()
+}
+
+const WORD: usize = size_of::<usize>();
+
+// Compares the bytes of two inline strings by words: they are equal if the first different
+// byte is after len. The inline capacity is a multiple of the word, so the words don't leave
+// the string.
+#[inline(always)]
+unsafe fn inline_eq<const N: usize, const M: usize>(
+    text: *const u8,
+    other: *const u8,
+    len: usize,
+) -> bool {
+    for i in 0..std::cmp::min(N, M) / WORD {
+        let diff = ptr::read(text.add(i * WORD) as *const usize)
+            ^ ptr::read(other.add(i * WORD) as *const usize);
+        if diff != 0 {
+            let byte = if cfg!(target_endian = "little") {
+                diff.trailing_zeros()
+            } else {
+                diff.leading_zeros()
+            } as usize
+                / 8;
+            return i * WORD + byte >= len;
+        }
+    }
+    true
+}
+
+// Like inline_eq, big-endian words compare like the bytes, the first byte is the highest
+#[inline(always)]
+unsafe fn inline_words(
+    text: *const u8,
+    other: *const u8,
+    len: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    (0..len.div_ceil(WORD)).map(move |i| {
+        let rest = len - i * WORD;
+        let mask = if rest >= WORD {
+            usize::MAX
+        } else {
+            !(usize::MAX >> (rest * 8))
+        };
+        let word = usize::from_be(ptr::read(text.add(i * WORD) as *const usize)) & mask;
+        let other = usize::from_be(ptr::read(other.add(i * WORD) as *const usize)) & mask;
+        (word, other)
+    })
+}
+
+/// Where the text of the string is stored.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Representation {
+    /// In the string itself.
+    Inline,
+    /// In the heap buffer owned only by this string.
+    Unique,
+    /// In the heap buffer shared with other strings or weak references,
+    /// so mutation copies it.
+    Shared,
+    /// In the string with static lifetime.
+    Static,
+}
+
+/// Marker of the inline capacity `N`, see [Capacity].
+pub struct Inline<const N: usize>;
+
+/// Implemented for the supported inline capacities: multiples of the pointer size
+/// from 16 bytes (8 bytes on 32-bit targets) up to 128 bytes.
+/// The string can't be smaller, because it must fit the pointer and len.
+pub trait Capacity: Buffer {}
+
+// Not reexported, so Capacity can't be implemented outside of the crate
+pub trait Buffer {
+    // Bytes before the tag byte
+    type Head: Copy;
+    const EMPTY: Self::Head;
+}
+
+macro_rules! inline_capacity {
+    ($($n:literal)*) => {
+        $(
+            impl Capacity for Inline<$n> {}
+
+            impl Buffer for Inline<$n> {
+                type Head = [u8; $n - 1];
+                const EMPTY: Self::Head = [0; $n - 1];
+            }
+
+            const _: () = [()][!($n % size_of::<usize>() == 0) as usize];
+            const _: () = [()][!($n >= 2 * size_of::<usize>()) as usize];
+            const _: () = [()][!($n - 2 <= u8::MAX as usize) as usize];
+        )*
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+inline_capacity!(16 24 32 40 48 56 64 72 80 88 96 104 112 120 128);
+#[cfg(target_pointer_width = "32")]
+inline_capacity!(
+    8 12 16 20 24 28 32 36 40 44 48 52 56 60 64
+    68 72 76 80 84 88 92 96 100 104 108 112 116 120 124 128
+);
+
+// Bytes of inline string or one of Medium, Large, Adopted, Static or Compact at the end,
+// which are accessed through pointer casts. Only the tag byte is typed: it's never zero,
+// so Option<StringInner> can use zero as None. The bytes are in UnsafeCell,
+// since share changes Medium to Adopted by the reference.
+#[repr(C)]
+pub struct StringInner<R: RefCount, const N: usize, A: Allocator>
+where
+    Inline<N>: Capacity,
+{
+    _align: [usize; 0],
+    head: UnsafeCell<<Inline<N> as Buffer>::Head>,
+    tag: NonZeroU8,
+    // Not Send and not Sync by default, because of the counter
+    _counter: PhantomData<*const R>,
+    // After the tag, so a zero-sized allocator doesn't change the layout
+    alloc: A,
+}
+
+type DefaultInner = StringInner<AtomicUsize, SMALL_CAPACITY, Global>;
+
+const _: () = [()][!(size_of::<Medium>() == HEAP_SIZE) as usize];
+const _: () = [()][!(size_of::<Static>() == HEAP_SIZE) as usize];
+const _: () = [()][!(size_of::<Adopted>() == HEAP_SIZE) as usize];
+const _: () = [()][!(size_of::<Compact<*const u8>>() == 2 * size_of::<usize>()) as usize];
+const _: () = [()][!(size_of::<ArcVecU8<AtomicUsize>>() == size_of::<usize>()) as usize];
+const _: () = [()][!(MAX_LEN >> 2 < KIND_MASK >> COMPACT_SHIFT) as usize];
+const _: () = [()][!(KIND_MASK >> COMPACT_SHIFT & !LARGE_MASK == 0) as usize];
+const _: () = [()][!(HEADER_ALIGN >= 4) as usize];
+const _: () = [()][!(size_of::<DefaultInner>() == SMALL_CAPACITY) as usize];
+const _: () = [()][!(size_of::<Option<DefaultInner>>() == size_of::<DefaultInner>()) as usize];
+const _: () = [()][!(size_of::<Option<StringInner<AtomicUsize, 64, Global>>>() == 64) as usize];
+const _: () = [()][!(SMALL_FLAG as usize + SMALL_SHORT <= SMALL_LONG_FLAG as usize) as usize];
+const _: () = [()][!(SMALL_LAST_FLAG < HEAP_FLAG) as usize];
+const _: () = [()][!(MAX_LEN < STATIC_BIT) as usize];
+const _: () = [()][!(offset_of!(DefaultInner, tag) == SMALL_CAPACITY - 1) as usize];
+const _: () =
+    [()][!(offset_of!(Large<AtomicUsize>, len) + size_of::<usize>() == HEAP_SIZE) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, data) == offset_of!(Medium, data)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, data) == offset_of!(Static, data)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, len) == offset_of!(Medium, len)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, len) == offset_of!(Static, len)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, offset) == offset_of!(Static, kind)) as usize];
+const _: () =
+    [()][!(offset_of!(Large<AtomicUsize>, offset) == offset_of!(Medium, capacity)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, data) == offset_of!(Adopted, data)) as usize];
+const _: () = [()][!(offset_of!(Large<AtomicUsize>, len) == offset_of!(Adopted, len)) as usize];
+const _: () =
+    [()][!(offset_of!(Large<AtomicUsize>, offset) == offset_of!(Adopted, header)) as usize];
+
+// Safety: the string is shared between threads only through the ArcVecU8 of Large or
+// the header of Adopted, all other states are owned (Small, Medium) or immutable (Static).
+// The only change by the reference is share of Medium, which Compact doesn't have:
+// the word before len is replaced by compare_exchange with Release and read atomically,
+// with Acquire before the header is accessed. The other words of Medium aren't changed,
+// so the text is read as usual.
+// ArcVecU8 is mutated only when get_mut sees the counter equal to 1 with Acquire,
+// which synchronizes with the Release decrement of every other owner in drop.
+// The last owner does the Acquire fence in decrement before dealloc for the same reason.
+// Clone increments the counter with Relaxed, the owner already has a reference,
+// like std::sync::Arc does. The weak counter is locked while get_mut checks the strong one,
+// so a weak reference can't be upgraded in between, unless WEAK_MARK shows there are none.
+// WeakInner holds the same data.
+unsafe impl<const N: usize, A: Allocator + Send> Send for StringInner<AtomicUsize, N, A> where
+    Inline<N>: Capacity
+{
+}
+unsafe impl<const N: usize, A: Allocator + Send + Sync> Sync for StringInner<AtomicUsize, N, A> where
+    Inline<N>: Capacity
+{
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Drop for StringInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            self.release_heap();
+        }
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Clone for StringInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn clone(&self) -> Self {
+        let alloc = self.alloc.clone();
+        if self.is_small() {
+            return unsafe { self.copy_in(alloc) };
+        }
+        if self.is_medium() {
+            // Medium is shared by the first clone
+            self.share();
+        }
+        unsafe {
+            // Static is just copied
+            if let Some(counted) = self.counted() {
+                counted.get_counter().increment();
+            }
+            self.copy_heap_in(alloc)
+        }
+    }
+}
+
+// Weak reference to Large or Adopted, which holds the weak counter instead of the strong one,
+// Medium is shared first. Inline and static strings are kept as is and always upgrade by clone.
+pub struct WeakInner<R: RefCount, const N: usize, A: Allocator>
+where
+    Inline<N>: Capacity,
+{
+    string: ManuallyDrop<StringInner<R, N, A>>,
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> WeakInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    pub fn new(string: &StringInner<R, N, A>) -> Self {
+        if string.is_medium() {
+            string.share();
+        }
+        let string = match unsafe { string.counted() } {
+            Some(counted) => unsafe {
+                counted.downgrade();
+                string.copy_in(string.alloc.clone())
+            },
+            None => string.clone(),
+        };
+        Self {
+            string: ManuallyDrop::new(string),
+        }
+    }
+
+    pub fn upgrade(&self) -> Option<StringInner<R, N, A>> {
+        unsafe {
+            match self.string.counted() {
+                Some(counted) if !counted.upgrade() => None,
+                Some(_) => Some(self.string.copy_in(self.string.alloc.clone())),
+                None => Some((*self.string).clone()),
+            }
+        }
+    }
+
+    // Strong owners of the shared buffer, None for other strings
+    pub fn strong_count(&self) -> Option<usize> {
+        self.string.strong_count()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Drop for WeakInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn drop(&mut self) {
+        unsafe {
+            match self.string.counted() {
+                Some(mut counted) => {
+                    counted.drop_weak(&self.string.alloc);
+                    ptr::drop_in_place(&mut self.string.alloc);
+                }
+                None => ManuallyDrop::drop(&mut self.string),
+            }
+        }
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Clone for WeakInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn clone(&self) -> Self {
+        let string = match unsafe { self.string.counted() } {
+            Some(counted) => unsafe {
+                counted.clone_weak();
+                self.string.copy_in(self.string.alloc.clone())
+            },
+            None => (*self.string).clone(),
+        };
+        Self {
+            string: ManuallyDrop::new(string),
+        }
+    }
 }
 
-impl ArcVecU8 {
-    pub unsafe fn with_capacity(capacity: usize) -> Self {
-        let pointer = alloc(Layout::from_size_align_unchecked(
-            CACHE_LINE_SIZE + capacity,
-            CACHE_LINE_SIZE,
-        ));
-        ptr::write(pointer as *mut AtomicUsize, AtomicUsize::new(1));
-        Self {
-            data: pointer.add(CACHE_LINE_SIZE),
-        }
+impl<R: RefCount, const N: usize, A: Allocator> StringInner<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    const COMPACT: bool = N < HEAP_SIZE;
+    const HEAP_LEN: usize = if Self::COMPACT {
+        size_of::<Compact<usize>>()
+    } else {
+        HEAP_SIZE
+    };
+    const HEAP_OFFSET: usize = N - Self::HEAP_LEN;
+    const LEN_OFFSET: usize = N - size_of::<usize>();
+    // Longer length would overwrite the kind of Compact: 128 MiB on 32-bit targets,
+    // 512 PiB on 64-bit targets
+    pub const MAX_LEN: usize = if Self::COMPACT { MAX_LEN >> 2 } else { MAX_LEN };
+
+    #[inline(always)]
+    fn check_len(len: usize) -> usize {
+        Self::try_check_len(len).unwrap_or_else(|error| error.handle())
+    }
+
+    #[inline(always)]
+    fn try_check_len(len: usize) -> Result<usize, TryReserveError> {
+        if len > Self::MAX_LEN {
+            return Err(TryReserveError::CapacityOverflow);
+        }
+        Ok(len)
+    }
+
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_tag(SMALL_FLAG, alloc)
+    }
+
+    // Copies the bytes with the new allocator, the counter of Large isn't changed
+    #[inline(always)]
+    unsafe fn copy_in(&self, alloc: A) -> Self {
+        let mut string = Self::new_in(alloc);
+        ptr::copy_nonoverlapping(self.small_ptr(), string.small_mut_ptr(), N);
+        string
+    }
+
+    // Copies Large, Adopted or Static with the new allocator, the counter isn't changed
+    #[inline(always)]
+    unsafe fn copy_heap_in(&self, alloc: A) -> Self {
+        let mut string = Self::with_tag(HEAP_FLAG, alloc);
+        ptr::copy_nonoverlapping(self.heap_ptr(), string.heap_mut_ptr(), Self::HEAP_LEN);
+        string
+    }
+
+    #[inline(always)]
+    fn with_tag(tag: u8, alloc: A) -> Self {
+        Self {
+            _align: [],
+            head: UnsafeCell::new(<Inline<N> as Buffer>::EMPTY),
+            tag: unsafe { NonZeroU8::new_unchecked(tag) },
+            _counter: PhantomData,
+            alloc,
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    // Owners of the shared buffer, None if the string doesn't have it
+    pub fn strong_count(&self) -> Option<usize> {
+        unsafe { self.counted().map(|counted| counted.get_counter().count()) }
+    }
+
+    // Cached in the header of the shared buffer, if the text starts the buffer
+    pub fn hash_code(&self) -> u64 {
+        let compute = || hash_state().hash_one(self.as_str());
+        unsafe {
+            if self.is_large() {
+                if self.large_offset() == 0 {
+                    return self.large_data().cached_hash(self.heap_len(), compute);
+                }
+            } else if self.is_adopted() {
+                let header = self.adopted_header();
+                if ptr::eq(self.heap_text_ptr(), header.string_buffer().data) {
+                    return header.cached_hash(self.heap_len(), compute);
+                }
+            }
+        }
+        compute()
+    }
+
+    pub fn representation(&self) -> Representation {
+        if self.is_small() {
+            Representation::Inline
+        } else if self.is_static() {
+            Representation::Static
+        } else if unsafe { self.counted() }.is_some_and(|counted| unsafe { counted.is_shared() }) {
+            Representation::Shared
+        } else {
+            Representation::Unique
+        }
+    }
+
+    // Bytes available from the start of the text, the len for Static
+    pub fn capacity(&self) -> usize {
+        unsafe {
+            if self.is_small() {
+                N
+            } else if self.is_large() {
+                self.large_data().capacity() - self.large_offset()
+            } else if self.is_static() {
+                self.heap_len()
+            } else if Self::COMPACT {
+                // Adopted, which starts the buffer
+                self.adopted_header().string_buffer().capacity
+            } else {
+                // Medium or Adopted, share can change the word in between, so it's read once
+                let word = self.heap_word().load(Ordering::Acquire);
+                if word & KIND_MASK == MEDIUM_BIT {
+                    word & !MEDIUM_BIT
+                } else {
+                    let buffer = ArcVecU8::<R>::from_word(word);
+                    let buffer = buffer.string_buffer();
+                    buffer.capacity - (self.heap_data() as usize - buffer.data as usize)
+                }
+            }
+        }
+    }
+
+    pub fn from_in(text: &str, alloc: A) -> Self {
+        Self::try_from_in(text, alloc).unwrap_or_else(|error| error.handle())
+    }
+
+    pub fn try_from_in(text: &str, alloc: A) -> Result<Self, TryReserveError> {
+        let new_len = text.len();
+        unsafe {
+            if Self::fits_small(new_len, text.as_bytes().last().copied().unwrap_or(0)) {
+                let mut new_string = Self::new_in(alloc);
+                ptr::copy_nonoverlapping(text.as_ptr(), new_string.small_mut_ptr(), new_len);
+                new_string.set_small_len(new_len);
+                Ok(new_string)
+            } else {
+                let new_capacity = align_capacity(Self::try_check_len(new_len)?);
+                let mut new_data = ArcVecU8::try_with_capacity(new_capacity, &alloc)?;
+                new_data.extend_from(0, text.as_ptr(), new_len);
+                Ok(Self::from_large(new_data, 0, new_len, alloc))
+            }
+        }
+    }
+
+    // Large even for a short text, so it has the counter
+    pub fn from_shared_in(text: &str, alloc: A) -> Self {
+        let new_len = Self::check_len(text.len());
+        unsafe {
+            let mut new_data = ArcVecU8::with_capacity(align_capacity(new_len), &alloc);
+            new_data.extend_from(0, text.as_ptr(), new_len);
+            Self::from_large(new_data, 0, new_len, alloc)
+        }
+    }
+
+    // The buffer of String is from the global allocator, so it's reallocated and deallocated
+    // there, A doesn't see it
+    pub fn from_string_in(string: String, alloc: A) -> Self {
+        let new_len = string.len();
+        if new_len <= N {
+            return Self::from_in(string.as_str(), alloc);
+        }
+        Self::check_len(new_len);
+        if Self::COMPACT {
+            // There is no Medium, the header is allocated at once
+            let mut string = ManuallyDrop::new(string);
+            let header = unsafe {
+                ArcVecU8::with_string_buffer(
+                    StringBuffer {
+                        data: string.as_mut_ptr(),
+                        capacity: string.capacity(),
+                    },
+                    &alloc,
+                )
+            };
+            return Self::from_adopted(string.as_ptr(), header, new_len, alloc);
+        }
+        // Otherwise the capacity would overwrite the kind bits
+        if string.capacity() > MAX_LEN {
+            return Self::from_in(string.as_str(), alloc);
+        }
+        let mut string = ManuallyDrop::new(string);
+        Self::from_heap(
+            Medium::new(string.as_mut_ptr(), string.capacity(), new_len),
+            alloc,
+        )
+    }
+
+    pub fn into_string(mut self) -> String {
+        if self.is_adopted() {
+            if Self::COMPACT {
+                if let Some(string) = self.take_adopted() {
+                    return string;
+                }
+            }
+            self.own_adopted();
+        }
+        if !self.is_medium() {
+            return String::from(self.as_str());
+        }
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let medium = this.medium();
+            String::from_raw_parts(medium.data, this.heap_len(), medium.capacity())
+        }
+    }
+
+    // The String buffer of unique compact Adopted, which starts it, the string becomes empty
+    #[inline(never)]
+    fn take_adopted(&mut self) -> Option<String> {
+        unsafe {
+            let mut header = self.adopted_header();
+            header.get_mut()?;
+            let buffer = ptr::read(header.string_buffer());
+            let string = String::from_raw_parts(buffer.data, self.heap_len(), buffer.capacity);
+            header.drop_slow(&self.alloc);
+            self.set_small_len(0);
+            Some(string)
+        }
+    }
+
+    pub fn into_counter<S: RefCount>(self) -> StringInner<S, N, A> {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            // Small, Medium and Static don't have the counter, the unique buffer
+            // of Large or Adopted gets the new counter in place
+            if let Some(counted) = this.counted() {
+                if !counted.is_unique() {
+                    let string = StringInner::from_in(this.as_str(), this.alloc.clone());
+                    ManuallyDrop::into_inner(this);
+                    return string;
+                }
+                counted.into_counter::<S>();
+            }
+            ptr::read(&*this as *const Self as *const StringInner<S, N, A>)
+        }
+    }
+
+    // Moves the heap buffer as is, an inline string is copied
+    pub fn into_capacity<const M: usize>(self) -> StringInner<R, M, A>
+    where
+        Inline<M>: Capacity,
+    {
+        if self.is_small() {
+            return StringInner::from_in(self.as_str(), self.alloc.clone());
+        }
+        if Self::COMPACT != StringInner::<R, M, A>::COMPACT {
+            return self.into_layout();
+        }
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            let mut string = StringInner::new_in(ptr::read(&this.alloc));
+            ptr::copy_nonoverlapping(this.heap_ptr(), string.heap_mut_ptr(), Self::HEAP_LEN);
+            string
+        }
+    }
+
+    // Moves the heap buffer from or to Compact, if the other layout can have it:
+    // Medium gets the header, substrings of Large and Adopted after the start are copied
+    #[inline(never)]
+    fn into_layout<const M: usize>(self) -> StringInner<R, M, A>
+    where
+        Inline<M>: Capacity,
+    {
+        unsafe {
+            if self.is_static() {
+                let text = &*(self.as_str() as *const str);
+                return StringInner::from_static_in(text, self.alloc.clone());
+            }
+            if self.is_medium() {
+                let alloc = self.alloc.clone();
+                return StringInner::from_string_in(self.into_string(), alloc);
+            }
+            let len = StringInner::<R, M, A>::check_len(self.heap_len());
+            if self.is_large() && self.large_offset() == 0 {
+                let this = ManuallyDrop::new(self);
+                let alloc = ptr::read(&this.alloc);
+                return StringInner::from_large(this.large_data(), 0, len, alloc);
+            }
+            if self.is_adopted() {
+                let header = self.adopted_header();
+                let text = self.heap_text_ptr();
+                if ptr::eq(text, header.string_buffer().data) {
+                    let this = ManuallyDrop::new(self);
+                    let alloc = ptr::read(&this.alloc);
+                    return StringInner::from_adopted(text, header, len, alloc);
+                }
+            }
+            StringInner::from_in(self.as_str(), self.alloc.clone())
+        }
+    }
+
+    pub fn from_static_in(text: &'static str, alloc: A) -> Self {
+        let new_len = text.len();
+        if new_len <= N {
+            return Self::from_in(text, alloc);
+        }
+        Self::check_len(new_len);
+        Self::from_static(text.as_ptr(), new_len, alloc)
+    }
+
+    // The text must be a part of self.as_str(), Large, Adopted and Static share it,
+    // Medium is shared by the first substring like by clone.
+    // Compact shares only the prefix.
+    pub fn substr(&self, text: &str) -> Self {
+        let len = text.len();
+        let alloc = self.alloc.clone();
+        if self.is_small() || Self::fits_small(len, text.as_bytes().last().copied().unwrap_or(0)) {
+            return Self::from_in(text, alloc);
+        }
+        if self.is_static() {
+            return Self::from_static(text.as_ptr(), len, alloc);
+        }
+        if Self::COMPACT && text.as_ptr() != self.as_str().as_ptr() {
+            return Self::from_in(text, alloc);
+        }
+        if self.is_medium() {
+            self.share();
+        }
+        unsafe {
+            if self.is_adopted() {
+                let header = self.adopted_header();
+                header.get_counter().increment();
+                return Self::from_adopted(text.as_ptr(), header, len, alloc);
+            }
+            let data = self.large_data();
+            let start = text.as_ptr() as usize - data.as_ptr() as usize;
+            Self::from_large(data.clone(), start, len, alloc)
+        }
+    }
+
+    #[inline(always)]
+    fn from_large(data: ArcVecU8<R>, offset: usize, len: usize, alloc: A) -> Self {
+        let mut string = Self::with_tag(HEAP_FLAG, alloc);
+        unsafe {
+            string.set_large(data, offset, len);
+        }
+        string
+    }
+
+    // Compact has no offset, so it must be zero there
+    #[inline(always)]
+    unsafe fn set_large(&mut self, data: ArcVecU8<R>, offset: usize, len: usize) {
+        if Self::COMPACT {
+            debug_assert!(offset == 0);
+            self.set_heap(Compact::new(data, 0, len));
+        } else {
+            self.set_heap(Large {
+                data,
+                offset,
+                len: pack_len(len),
+            });
+        }
+    }
+
+    #[inline(always)]
+    fn from_static(data: *const u8, len: usize, alloc: A) -> Self {
+        if Self::COMPACT {
+            Self::from_heap(Compact::new(data, STATIC_BIT, len), alloc)
+        } else {
+            Self::from_heap(Static::new(data, len), alloc)
+        }
+    }
+
+    // The text at data is in the String buffer of the header, at its start for Compact
+    #[inline(always)]
+    fn from_adopted(data: *const u8, header: ArcVecU8<R>, len: usize, alloc: A) -> Self {
+        if Self::COMPACT {
+            Self::from_heap(Compact::new(header, KIND_MASK, len), alloc)
+        } else {
+            Self::from_heap(
+                Adopted {
+                    data,
+                    header: header.to_word(),
+                    len: pack_len(len),
+                },
+                alloc,
+            )
+        }
+    }
+
+    #[inline(always)]
+    fn from_heap<T>(heap: T, alloc: A) -> Self {
+        // The tag is overwritten by the len of heap
+        let mut string = Self::with_tag(HEAP_FLAG, alloc);
+        unsafe {
+            string.set_heap(heap);
+        }
+        string
+    }
+
+    // Replaces the state without drop, the allocator stays
+    #[inline(always)]
+    unsafe fn set_heap<T>(&mut self, heap: T) {
+        ptr::write(self.heap_mut_ptr() as *mut T, heap);
+    }
+
+    #[inline(always)]
+    fn heap_ptr(&self) -> *const u8 {
+        unsafe { (self as *const Self as *const u8).add(Self::HEAP_OFFSET) }
+    }
+
+    #[inline(always)]
+    fn heap_mut_ptr(&mut self) -> *mut u8 {
+        unsafe { (self as *mut Self as *mut u8).add(Self::HEAP_OFFSET) }
+    }
+
+    #[inline(always)]
+    unsafe fn medium(&self) -> &Medium {
+        debug_assert!(!Self::COMPACT);
+        &*(self.heap_ptr() as *const Medium)
+    }
+
+    #[inline(always)]
+    unsafe fn medium_mut(&mut self) -> &mut Medium {
+        debug_assert!(!Self::COMPACT);
+        &mut *(self.heap_mut_ptr() as *mut Medium)
     }
 
-    #[inline(never)]
-    unsafe fn drop_slow(&mut self, capacity: usize) {
-        std::sync::atomic::fence(Ordering::Acquire);
-        dealloc(
-            self.data.sub(CACHE_LINE_SIZE),
-            Layout::from_size_align_unchecked(CACHE_LINE_SIZE + capacity, CACHE_LINE_SIZE),
-        );
+    // A copy of the buffer pointer of Large, the first word in both layouts
+    #[inline(always)]
+    unsafe fn large_data(&self) -> ArcVecU8<R> {
+        ptr::read(self.heap_ptr() as *const ArcVecU8<R>)
     }
 
-    #[inline]
-    pub unsafe fn drop(&mut self, capacity: usize) {
-        if self.get_counter().fetch_sub(1, Ordering::Release) != 1 {
-            return;
+    // Large is in the inline bytes and the allocator is after them, so they don't overlap
+    #[inline(always)]
+    unsafe fn large_data_alloc_mut(&mut self) -> (&mut ArcVecU8<R>, &A) {
+        let alloc = &*ptr::addr_of!(self.alloc);
+        (&mut *(self.heap_mut_ptr() as *mut ArcVecU8<R>), alloc)
+    }
+
+    // Compact Large always starts the buffer
+    #[inline(always)]
+    unsafe fn large_offset(&self) -> usize {
+        if Self::COMPACT {
+            0
+        } else {
+            ptr::read(self.heap_ptr().add(offset_of!(Large<R>, offset)) as *const usize)
         }
-        self.drop_slow(capacity);
     }
 
-    pub unsafe fn get_mut(&mut self) -> Option<&mut ArcVecU8> {
-        if self.get_counter().load(Ordering::Acquire) == 1 {
-            Some(self)
+    // A copy of the header pointer of Adopted, the first word of Compact
+    #[inline(always)]
+    unsafe fn adopted_header(&self) -> ArcVecU8<R> {
+        if Self::COMPACT {
+            ptr::read(self.heap_ptr() as *const ArcVecU8<R>)
+        } else {
+            ArcVecU8::from_word(self.heap_word().load(Ordering::Acquire))
+        }
+    }
+
+    // A copy of the buffer pointer with the counters: of Large or the header of Adopted
+    #[inline(always)]
+    unsafe fn counted(&self) -> Option<ArcVecU8<R>> {
+        if self.is_large() {
+            Some(self.large_data())
+        } else if self.is_adopted() {
+            Some(self.adopted_header())
         } else {
             None
         }
     }
 
-    pub unsafe fn reserve(&mut self, old_capacity: usize, new_capacity: usize) {
-        // TODO old_capacity should be old_size or old_capacity?
-        //      If capacity its strange, I want try_realloc and if null,
-        //      alloc new_capacity and copy only old_size
-        self.data = realloc(
-            self.data.sub(CACHE_LINE_SIZE),
-            Layout::from_size_align_unchecked(CACHE_LINE_SIZE + old_capacity, CACHE_LINE_SIZE),
-            CACHE_LINE_SIZE + new_capacity,
-        )
-        .add(CACHE_LINE_SIZE);
+    // The first word of Medium, Large, Adopted or Static, share doesn't change it
+    #[inline(always)]
+    unsafe fn heap_data(&self) -> *mut u8 {
+        ptr::read(self.heap_ptr() as *const *mut u8)
     }
 
-    pub unsafe fn extend_from(&mut self, old_size: usize, bytes: *const u8, size: usize) {
-        ptr::copy_nonoverlapping(bytes, self.data.add(old_size), size);
+    // The last word, share doesn't change it either
+    #[inline(always)]
+    unsafe fn heap_len_word(&self) -> usize {
+        usize::from_le(ptr::read(
+            self.small_ptr().add(Self::LEN_OFFSET) as *const usize
+        ))
     }
 
-    pub fn as_ptr(&self) -> *const u8 {
-        self.data
+    // Without the tag and the kind of Compact
+    #[inline(always)]
+    unsafe fn heap_len(&self) -> usize {
+        self.heap_len_word() & Self::MAX_LEN
     }
 
-    pub fn as_mut_ptr(&self) -> *mut u8 {
-        self.data
+    // Keeps the tag and the kind of Compact
+    #[inline(always)]
+    unsafe fn set_heap_len(&mut self, new_len: usize) {
+        let word = self.heap_len_word() & !Self::MAX_LEN | new_len;
+        ptr::write(
+            self.small_mut_ptr().add(Self::LEN_OFFSET) as *mut usize,
+            word.to_le(),
+        );
     }
 
+    // The text of Medium, Large, Adopted or Static
     #[inline(always)]
-    unsafe fn get_counter(&self) -> &AtomicUsize {
-        &*(self.data.sub(CACHE_LINE_SIZE) as *mut AtomicUsize)
+    unsafe fn heap_text_ptr(&self) -> *const u8 {
+        if self.is_large() {
+            self.large_data().as_ptr().add(self.large_offset())
+        } else if Self::COMPACT && self.is_adopted() {
+            self.adopted_header().string_buffer().data
+        } else {
+            self.heap_data()
+        }
     }
 
     #[inline(always)]
-    pub fn align_capacity(capacity: usize) -> usize {
-        ((capacity + CACHE_LINE_SIZE - 1) / CACHE_LINE_SIZE) * CACHE_LINE_SIZE
+    fn small_ptr(&self) -> *const u8 {
+        self as *const Self as *const u8
     }
-}
 
-impl Clone for ArcVecU8 {
-    fn clone(&self) -> Self {
-        unsafe {
-            self.get_counter().fetch_add(1, Ordering::Relaxed);
+    #[inline(always)]
+    fn small_mut_ptr(&mut self) -> *mut u8 {
+        self as *mut Self as *mut u8
+    }
+
+    #[inline(always)]
+    fn small_len(&self) -> usize {
+        let tag = self.tag.get();
+        // The last byte of the string gives a value greater than N
+        let len = tag.wrapping_sub(SMALL_FLAG) as usize;
+        if N <= SMALL_SHORT {
+            return std::cmp::min(len, N);
+        }
+        if len < SMALL_SHORT {
+            len
+        } else if tag < SMALL_FLAG {
+            N
+        } else if tag == SMALL_LAST_FLAG {
+            N - 1
+        } else {
+            unsafe { *self.small_ptr().add(N - 2) as usize }
         }
-        Self { data: self.data }
     }
-}
 
-//               large len: 255 255 255 255
-// little endian last byte: x..
-//    big endian last byte:             ..x this is what we want
-//                                      x.. but this is what we get if the code is the same for big endian
-#[cfg(target_endian = "little")]
-#[derive(Clone)]
-#[repr(C)]
-struct Large {
-    data: ArcVecU8,
-    capacity: usize,
-    len: usize,
-}
+    // Sets the tag of the string with the new_len, its bytes must be written before
+    #[inline(always)]
+    fn set_small_len(&mut self, new_len: usize) {
+        if new_len < std::cmp::min(N, SMALL_SHORT) {
+            self.tag = unsafe { NonZeroU8::new_unchecked(SMALL_FLAG | new_len as u8) };
+        } else if new_len == N {
+            // The tag is the last byte of the string
+        } else if new_len == N - 1 {
+            self.tag = unsafe { NonZeroU8::new_unchecked(SMALL_LAST_FLAG) };
+        } else {
+            unsafe {
+                *self.small_mut_ptr().add(N - 2) = new_len as u8;
+            }
+            self.tag = unsafe { NonZeroU8::new_unchecked(SMALL_LONG_FLAG) };
+        }
+    }
 
-// TODO this work only on little endian
-const SMALL_CAPACITY: usize = size_of::<Large>() - 1;
-const LARGE_FLAG: u8 = 0x80;
-const LARGE_SHIFT: usize = (size_of::<usize>() - 1) * 8;
-const LARGE_MASK: usize = !((LARGE_FLAG as usize) << LARGE_SHIFT);
-const LARGE_BIT: usize = (LARGE_FLAG as usize) << LARGE_SHIFT;
+    // The tag byte of the full string is its last byte, which must not be zero
+    #[inline(always)]
+    fn fits_small(new_len: usize, last: u8) -> bool {
+        new_len < N || (new_len == N && last != 0)
+    }
 
-#[derive(Clone, Copy)]
-#[repr(C)]
-struct Small {
-    data: [u8; SMALL_CAPACITY],
-    len: u8,
-}
+    #[inline(always)]
+    fn is_small(&self) -> bool {
+        self.tag.get() < HEAP_FLAG
+    }
 
-const _: () = [()][!(size_of::<Small>() == size_of::<Large>()) as usize];
+    // The word before len, for the inline string it's just its bytes.
+    // It's atomic, since share replaces it in Medium by the reference.
+    #[inline(always)]
+    fn heap_word(&self) -> &AtomicUsize {
+        unsafe { &*(self.heap_ptr().add(offset_of!(Medium, capacity)) as *const AtomicUsize) }
+    }
 
-#[repr(C)]
-pub union StringInner {
-    small: Small,
-    large: ManuallyDrop<Large>,
-}
+    // The checks of the kind use & instead of &&, so each is a single branch
+    #[inline(always)]
+    fn heap_kind(&self) -> usize {
+        if Self::COMPACT {
+            (unsafe { self.heap_len_word() } << COMPACT_SHIFT) & KIND_MASK
+        } else {
+            self.heap_word().load(Ordering::Relaxed) & KIND_MASK
+        }
+    }
 
-impl Drop for StringInner {
-    #[inline]
-    fn drop(&mut self) {
+    #[inline(always)]
+    fn is_medium(&self) -> bool {
+        !Self::COMPACT & !self.is_small() & (self.heap_kind() == MEDIUM_BIT)
+    }
+
+    // With Acquire, so the header can be read after it, Compact is never shared by reference
+    #[inline(always)]
+    fn is_adopted(&self) -> bool {
+        let kind = if Self::COMPACT {
+            self.heap_kind()
+        } else {
+            self.heap_word().load(Ordering::Acquire) & KIND_MASK
+        };
+        !self.is_small() & (kind == KIND_MASK)
+    }
+
+    #[inline(always)]
+    fn is_large(&self) -> bool {
+        !self.is_small() & (self.heap_kind() == 0)
+    }
+
+    #[inline(always)]
+    fn is_static(&self) -> bool {
+        !self.is_small() & (self.heap_kind() == STATIC_BIT)
+    }
+
+    // Medium becomes Adopted on the first clone or substring: its buffer gets the header
+    // with the counters, so it can be shared.
+    // The word before len is replaced by the reference, so it's done once by one thread,
+    // the header of the other one is freed.
+    #[inline(never)]
+    fn share(&self) {
+        let word = self.heap_word();
+        let capacity = word.load(Ordering::Acquire);
+        if capacity & KIND_MASK != MEDIUM_BIT {
+            return;
+        }
         unsafe {
-            if self.is_large() {
-                let mut large = ManuallyDrop::take(&mut self.large);
-                large.data.drop(large.capacity);
+            let mut header = ArcVecU8::<R>::with_string_buffer(
+                StringBuffer {
+                    data: self.heap_data(),
+                    capacity: capacity & !MEDIUM_BIT,
+                },
+                &self.alloc,
+            );
+            if word
+                .compare_exchange(
+                    capacity,
+                    header.to_word(),
+                    Ordering::Release,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                header.drop_slow(&self.alloc);
             }
         }
     }
-}
 
-impl Clone for StringInner {
-    fn clone(&self) -> Self {
+    // Adopted becomes Medium again, if it's unique, its text starts the String buffer
+    // and the capacity fits Medium: compact from_string_in doesn't check it, into_layout
+    // can bring such buffer here. Compact has no Medium, so it stays.
+    #[inline(never)]
+    fn own_adopted(&mut self) {
+        if Self::COMPACT {
+            return;
+        }
         unsafe {
-            if self.is_large() {
-                Self {
-                    large: self.large.clone(),
+            let mut header = self.adopted_header();
+            let buffer = ptr::read(header.string_buffer());
+            if buffer.data == self.heap_data()
+                && buffer.capacity <= MAX_LEN
+                && header.get_mut().is_some()
+            {
+                header.drop_slow(&self.alloc);
+                self.medium_mut().set_capacity(buffer.capacity);
+            }
+        }
+    }
+
+    // Copies the static or shared text to own large buffer, before the mutation in place
+    #[inline(never)]
+    fn unshare(&mut self) {
+        let new_capacity = align_capacity(self.as_str().len());
+        unsafe {
+            if let Err(error) = self.try_to_large(new_capacity) {
+                error.handle();
+            }
+        }
+    }
+
+    // Copies the string to a new unique Large with new_capacity, which isn't less than its len,
+    // then releases the old state. On failure the string isn't changed.
+    #[inline(never)]
+    unsafe fn try_to_large(&mut self, new_capacity: usize) -> Result<(), TryReserveError> {
+        let text = self.as_str();
+        let len = text.len();
+        let mut new_data = ArcVecU8::try_with_capacity(new_capacity, &self.alloc)?;
+        new_data.extend_from(0, text.as_ptr(), len);
+        self.release_heap();
+        self.set_large(new_data, 0, len);
+        Ok(())
+    }
+
+    // Frees Medium or decrements Large or Adopted, the string must be overwritten after it
+    #[inline(always)]
+    unsafe fn release_heap(&mut self) {
+        if self.is_large() {
+            let (data, alloc) = self.large_data_alloc_mut();
+            data.drop(alloc);
+        } else if self.is_medium() {
+            let medium = self.medium();
+            StringBuffer {
+                data: medium.data,
+                capacity: medium.capacity(),
+            }
+            .dealloc();
+        } else if self.is_adopted() {
+            let mut header = self.adopted_header();
+            if header.release() {
+                header.string_buffer().dealloc();
+                header.drop_weak(&self.alloc);
+            }
+        }
+    }
+
+    // Medium or unique Large, which can be changed in place. Unique Adopted becomes Medium.
+    #[inline(always)]
+    fn owns_heap(&mut self) -> bool {
+        if self.is_adopted() {
+            self.own_adopted();
+        }
+        self.is_medium()
+            || (self.is_large() && unsafe { self.large_data_alloc_mut().0.get_mut().is_some() })
+    }
+
+    // Copies the text with the bytes start..end replaced by the string to a new unique Large,
+    // so the shared or static text is copied once
+    #[inline(never)]
+    unsafe fn splice_to_large(&mut self, start: usize, end: usize, string: &str) {
+        let text = self.as_str();
+        let len = text.len();
+        let new_len = Self::check_len(len - (end - start) + string.len());
+        let mut new_data = ArcVecU8::with_capacity(align_capacity(new_len), &self.alloc);
+        new_data.extend_from(0, text.as_ptr(), start);
+        new_data.extend_from(start, string.as_ptr(), string.len());
+        new_data.extend_from(start + string.len(), text.as_ptr().add(end), len - end);
+        self.release_heap();
+        self.set_large(new_data, 0, new_len);
+    }
+
+    // Moves Medium or Large back inline, the text must fit
+    #[inline(never)]
+    unsafe fn demote(&mut self) {
+        let len = self.as_str().len();
+        let mut temp = [0u8; N];
+        ptr::copy_nonoverlapping(self.heap_text_ptr(), temp.as_mut_ptr(), len);
+        self.release_heap();
+        ptr::copy_nonoverlapping(temp.as_ptr(), self.small_mut_ptr(), len);
+        self.set_small_len(len);
+    }
+
+    #[inline(always)]
+    fn fits_small_now(&self) -> bool {
+        let text = self.as_str();
+        Self::fits_small(text.len(), text.as_bytes().last().copied().unwrap_or(0))
+    }
+
+    // With the auto-demote feature the heap string which became short enough moves inline,
+    // even if it's shared, called after every operation which makes the string shorter
+    #[inline(always)]
+    fn auto_demote(&mut self) {
+        if cfg!(feature = "auto-demote")
+            && !self.is_small()
+            && !self.is_static()
+            && self.fits_small_now()
+        {
+            unsafe { self.demote() }
+        }
+    }
+
+    // Reduces the capacity to max(len, min_capacity), the shared buffer is copied
+    // only if it's bigger. The string moves inline if it fits and min_capacity allows.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        if self.is_small() || self.is_static() {
+            return;
+        }
+        if min_capacity <= N && self.fits_small_now() {
+            unsafe { self.demote() };
+            return;
+        }
+        let target = std::cmp::max(self.as_str().len(), min_capacity);
+        unsafe {
+            if self.is_adopted() {
+                self.own_adopted();
+            }
+            if self.is_medium() {
+                let medium = self.medium_mut();
+                if target < medium.capacity() {
+                    let new_data = realloc(
+                        medium.data,
+                        Layout::from_size_align_unchecked(medium.capacity(), 1),
+                        target,
+                    );
+                    if new_data.is_null() {
+                        handle_alloc_error(Layout::from_size_align_unchecked(target, 1));
+                    }
+                    medium.data = new_data;
+                    medium.set_capacity(target);
                 }
+                return;
+            }
+            let target = align_capacity(target);
+            if self.is_adopted() {
+                if target < self.capacity() {
+                    if let Err(error) = self.try_to_large(target) {
+                        error.handle();
+                    }
+                }
+                return;
+            }
+            let offset = self.large_offset();
+            let (data, alloc) = self.large_data_alloc_mut();
+            if target >= data.capacity() {
+                return;
+            }
+            let result = if offset == 0 && data.get_mut().is_some() {
+                data.try_realloc(target, alloc)
             } else {
-                Self { small: self.small }
+                self.try_to_large(target)
+            };
+            if let Err(error) = result {
+                error.handle();
             }
         }
     }
-}
 
-impl StringInner {
-    pub fn new() -> Self {
-        StringInner {
-            small: Small {
-                data: [0; SMALL_CAPACITY],
-                len: 0,
-            },
+    // After success the string is Medium or unique Large with capacity for additional bytes,
+    // except the inline string, which stays inline if it fits
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.try_grow(additional, false)
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        if let Err(error) = self.try_reserve(additional) {
+            error.handle();
         }
     }
 
-    pub fn from(text: &str) -> Self {
-        let new_len = text.len();
+    pub fn reserve_exact(&mut self, additional: usize) {
+        if let Err(error) = self.try_reserve_exact(additional) {
+            error.handle();
+        }
+    }
+
+    // Like try_reserve, but without the amortized growth
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.try_grow(additional, true)
+    }
+
+    fn try_grow(&mut self, additional: usize, exact: bool) -> Result<(), TryReserveError> {
+        if additional == 0 {
+            return Ok(());
+        }
+        let len = self.as_str().len();
+        let new_len = Self::try_check_len(
+            len.checked_add(additional)
+                .ok_or(TryReserveError::CapacityOverflow)?,
+        )?;
         unsafe {
-            if new_len <= SMALL_CAPACITY {
-                let mut new_data = [0; SMALL_CAPACITY];
-                ptr::copy_nonoverlapping(text.as_ptr(), new_data.as_mut_ptr(), new_len);
-                Self {
-                    small: Small {
-                        data: new_data,
-                        len: new_len as u8,
-                    },
+            if self.is_adopted() {
+                self.own_adopted();
+            }
+            if self.is_medium() {
+                let medium = self.medium_mut();
+                let capacity = medium.capacity();
+                if capacity < new_len {
+                    let new_capacity = if exact {
+                        new_len
+                    } else {
+                        std::cmp::max(new_len, std::cmp::min(capacity * 2, MAX_LEN))
+                    };
+                    let new_data = realloc(
+                        medium.data,
+                        Layout::from_size_align_unchecked(capacity, 1),
+                        new_capacity,
+                    );
+                    if new_data.is_null() {
+                        return Err(TryReserveError::AllocError {
+                            layout: Layout::from_size_align_unchecked(new_capacity, 1),
+                        });
+                    }
+                    medium.data = new_data;
+                    medium.set_capacity(new_capacity);
                 }
-            } else {
-                let new_capacity = ArcVecU8::align_capacity(new_len);
-                let mut new_data = ArcVecU8::with_capacity(new_capacity);
-                new_data.extend_from(0, text.as_ptr(), new_len);
-                Self {
-                    large: ManuallyDrop::new(Large {
-                        data: new_data,
-                        capacity: new_capacity,
-                        len: new_len | LARGE_BIT,
-                    }),
+                return Ok(());
+            }
+            if self.is_large() {
+                let offset = self.large_offset();
+                let (data, alloc) = self.large_data_alloc_mut();
+                if let Some(old_data) = data.get_mut() {
+                    let capacity = old_data.capacity();
+                    if offset + new_len <= capacity {
+                        return Ok(());
+                    }
+                    // Otherwise the substring is moved to the start of a new buffer
+                    if offset == 0 {
+                        let new_capacity = if exact {
+                            align_capacity(new_len)
+                        } else {
+                            align_capacity(std::cmp::max(new_len, capacity * 3 / 2))
+                        };
+                        old_data.try_realloc(new_capacity, alloc)?;
+                        return Ok(());
+                    }
                 }
+            } else if self.is_small() && new_len <= N {
+                return Ok(());
             }
+            // Small, Static, shared Large or Adopted
+            self.try_to_large(align_capacity(new_len))
         }
     }
 
+    // Inline strings are compared by words, strings with the same text pointer by len
     #[inline(always)]
-    fn is_large(&self) -> bool {
-        unsafe { (self.small.len & LARGE_FLAG) == LARGE_FLAG }
+    pub fn eq<const M: usize, B: Allocator>(&self, other: &StringInner<R, M, B>) -> bool
+    where
+        Inline<M>: Capacity,
+    {
+        if self.is_small() && other.is_small() {
+            let len = self.small_len();
+            // With the same capacity the tag gives the len, except the long one
+            let same_len = if N == M && self.tag.get() != SMALL_LONG_FLAG {
+                self.tag == other.tag
+            } else {
+                len == other.small_len()
+            };
+            return same_len
+                && unsafe { inline_eq::<N, M>(self.small_ptr(), other.small_ptr(), len) };
+        }
+        let (text, other) = (self.as_str(), other.as_str());
+        text.len() == other.len() && (text.as_ptr() == other.as_ptr() || text == other)
+    }
+
+    pub fn cmp<const M: usize, B: Allocator>(&self, other: &StringInner<R, M, B>) -> cmp::Ordering
+    where
+        Inline<M>: Capacity,
+    {
+        if self.is_small() && other.is_small() {
+            let (len, other_len) = (self.small_len(), other.small_len());
+            let words = unsafe {
+                inline_words(
+                    self.small_ptr(),
+                    other.small_ptr(),
+                    std::cmp::min(len, other_len),
+                )
+            };
+            for (word, other) in words {
+                if word != other {
+                    return word.cmp(&other);
+                }
+            }
+            return len.cmp(&other_len);
+        }
+        let (text, other) = (self.as_str(), other.as_str());
+        if text.as_ptr() == other.as_ptr() {
+            // One is the prefix of the other
+            return text.len().cmp(&other.len());
+        }
+        text.cmp(other)
     }
 
     pub fn as_str(&self) -> &str {
         unsafe {
-            from_utf8_unchecked(if self.is_large() {
-                slice::from_raw_parts(self.large.data.as_ptr(), self.large.len & LARGE_MASK)
+            // Large, Medium, Adopted and Static have the same layout
+            from_utf8_unchecked(if !self.is_small() {
+                slice::from_raw_parts(self.heap_text_ptr(), self.heap_len())
             } else {
-                slice::from_raw_parts(self.small.data.as_ptr(), self.small.len as usize)
+                slice::from_raw_parts(self.small_ptr(), self.small_len())
             })
         }
     }
 
+    // The copy on write step: after it the string is inline, Medium or unique Large
+    pub fn as_mut_str(&mut self) -> &mut str {
+        let len = self.as_str().len();
+        unsafe {
+            // The tag of the full inline string is its last byte, which the caller could
+            // set to zero, valid in UTF-8, so the full string moves to the heap
+            if self.is_small() && len < N {
+                return from_utf8_unchecked_mut(slice::from_raw_parts_mut(
+                    self.small_mut_ptr(),
+                    len,
+                ));
+            }
+            if !self.owns_heap() {
+                self.unshare();
+            }
+            from_utf8_unchecked_mut(slice::from_raw_parts_mut(
+                self.heap_text_ptr() as *mut u8,
+                len,
+            ))
+        }
+    }
+
     pub fn push_str(&mut self, string: &str) {
+        if let Err(error) = self.try_push_str(string) {
+            error.handle();
+        }
+    }
+
+    pub fn try_push_str(&mut self, string: &str) -> Result<(), TryReserveError> {
         let str_len = string.len();
+        if str_len == 0 {
+            return Ok(());
+        }
         unsafe {
-            if self.is_large() {
-                let old_len = self.large.len & LARGE_MASK;
-                let new_len = old_len + str_len;
-                let mut capacity = self.large.capacity;
-                match self.large.data.get_mut() {
-                    Some(old_data) => {
-                        if capacity < new_len {
-                            let new_capacity =
-                                ArcVecU8::align_capacity(std::cmp::max(new_len, capacity * 3 / 2));
-                            old_data.reserve(capacity, new_capacity);
-                            capacity = new_capacity;
-                        }
-                        old_data.extend_from(old_len, string.as_ptr(), str_len);
-                    }
-                    None => {
-                        let new_capacity = ArcVecU8::align_capacity(new_len);
-                        let mut new_data = ArcVecU8::with_capacity(new_capacity);
-                        new_data.extend_from(0, self.large.data.as_ptr(), old_len);
-                        new_data.extend_from(old_len, string.as_ptr(), str_len);
-                        self.large.data.drop(capacity);
-                        self.large.data = new_data;
-                        capacity = new_capacity;
-                    }
-                }
-                self.large.capacity = capacity;
-                self.large.len = new_len | LARGE_BIT;
-            } else {
-                let old_len = self.small.len as usize;
+            if self.is_small() {
+                let old_len = self.small_len();
                 let new_len = old_len + str_len;
-                if new_len <= SMALL_CAPACITY {
+                if Self::fits_small(new_len, string.as_bytes()[str_len - 1]) {
                     ptr::copy_nonoverlapping(
                         string.as_ptr(),
-                        self.small.data.as_mut_ptr().add(old_len),
+                        self.small_mut_ptr().add(old_len),
                         str_len,
                     );
-                    self.small.len = new_len as u8;
-                } else {
-                    let new_capacity = ArcVecU8::align_capacity(new_len);
-                    let mut new_data = ArcVecU8::with_capacity(new_capacity);
-                    new_data.extend_from(0, self.small.data.as_ptr(), old_len);
-                    new_data.extend_from(old_len, string.as_ptr(), str_len);
-                    *self = StringInner {
-                        large: ManuallyDrop::new(Large {
-                            data: new_data,
-                            capacity: new_capacity,
-                            len: new_len | LARGE_BIT,
-                        }),
+                    self.set_small_len(new_len);
+                    return Ok(());
+                }
+                self.try_to_large(align_capacity(Self::try_check_len(new_len)?))?;
+            } else {
+                self.try_reserve(str_len)?;
+            }
+            // Medium or unique Large with enough capacity
+            let text = self.heap_text_ptr() as *mut u8;
+            let old_len = self.heap_len();
+            ptr::copy_nonoverlapping(string.as_ptr(), text.add(old_len), str_len);
+            self.set_heap_len(old_len + str_len);
+        }
+        Ok(())
+    }
+
+    // Only the len is changed, the shared or static text isn't copied.
+    // The new_len must be on a char boundary.
+    pub fn truncate(&mut self, new_len: usize) {
+        if new_len >= self.as_str().len() {
+            return;
+        }
+        if self.is_small() {
+            self.set_small_len(new_len);
+            return;
+        }
+        unsafe {
+            self.set_heap_len(new_len);
+        }
+        self.auto_demote();
+    }
+
+    // Keeps the own buffer, unless auto-demote moves the string inline,
+    // the shared or static one is released
+    pub fn clear(&mut self) {
+        if self.is_small() || self.owns_heap() {
+            self.truncate(0);
+            return;
+        }
+        unsafe {
+            self.release_heap();
+        }
+        self.set_small_len(0);
+    }
+
+    // Replaces the bytes start..end, which must be on char boundaries, by the string.
+    // The own text is changed in place, the shared or static text is copied once,
+    // or becomes a substring of itself if its prefix or suffix is removed.
+    pub fn replace_range(&mut self, start: usize, end: usize, string: &str) {
+        let text = self.as_str();
+        let len = text.len();
+        let str_len = string.len();
+        let new_len = len - (end - start) + str_len;
+        let last = if end < len {
+            text.as_bytes()[len - 1]
+        } else if str_len > 0 {
+            string.as_bytes()[str_len - 1]
+        } else if start > 0 {
+            text.as_bytes()[start - 1]
+        } else {
+            0
+        };
+        let fits = Self::fits_small(new_len, last);
+        unsafe {
+            if self.is_small() && fits {
+                let data = self.small_mut_ptr();
+                ptr::copy(data.add(end), data.add(start + str_len), len - end);
+                ptr::copy_nonoverlapping(string.as_ptr(), data.add(start), str_len);
+                self.set_small_len(new_len);
+                return;
+            }
+            if self.is_small() || !self.owns_heap() {
+                let text = self.as_str();
+                if fits {
+                    let mut small = Self::new_in(self.alloc.clone());
+                    let data = small.small_mut_ptr();
+                    ptr::copy_nonoverlapping(text.as_ptr(), data, start);
+                    ptr::copy_nonoverlapping(string.as_ptr(), data.add(start), str_len);
+                    ptr::copy_nonoverlapping(
+                        text.as_ptr().add(end),
+                        data.add(start + str_len),
+                        len - end,
+                    );
+                    small.set_small_len(new_len);
+                    *self = small;
+                } else if str_len == 0 && (start == 0 || end == len) {
+                    let rest = if start == 0 {
+                        &text[end..]
+                    } else {
+                        &text[..start]
                     };
+                    *self = self.substr(rest);
+                } else {
+                    self.splice_to_large(start, end, string);
+                }
+                return;
+            }
+            if new_len > len {
+                self.reserve(new_len - len);
+            }
+            // Medium or unique Large with enough capacity
+            let data = self.heap_text_ptr() as *mut u8;
+            ptr::copy(data.add(end), data.add(start + str_len), len - end);
+            ptr::copy_nonoverlapping(string.as_ptr(), data.add(start), str_len);
+            self.set_heap_len(new_len);
+        }
+        self.auto_demote();
+    }
+
+    // Removes the chars in place, the shared or static text is copied once before
+    pub fn retain<F: FnMut(char) -> bool>(&mut self, mut f: F) {
+        // On panic in f the processed part is kept
+        struct Guard<'a, R: RefCount, const N: usize, A: Allocator>
+        where
+            Inline<N>: Capacity,
+        {
+            string: &'a mut StringInner<R, N, A>,
+            idx: usize,
+            deleted: usize,
+        }
+
+        impl<R: RefCount, const N: usize, A: Allocator> Drop for Guard<'_, R, N, A>
+        where
+            Inline<N>: Capacity,
+        {
+            fn drop(&mut self) {
+                self.string.truncate(self.idx - self.deleted);
+            }
+        }
+
+        let len = self.as_str().len();
+        if !self.is_small() && !self.owns_heap() {
+            self.unshare();
+        }
+        let mut guard = Guard {
+            string: self,
+            idx: 0,
+            deleted: 0,
+        };
+        // Only the bytes before the len are written, so the tag of the inline string stays,
+        // its last byte is written only if it doesn't move
+        let data = if guard.string.is_small() {
+            guard.string.small_mut_ptr()
+        } else {
+            unsafe { guard.string.heap_text_ptr() as *mut u8 }
+        };
+        while guard.idx < len {
+            let ch = unsafe {
+                from_utf8_unchecked(slice::from_raw_parts(data.add(guard.idx), len - guard.idx))
+                    .chars()
+                    .next()
+                    .unwrap_unchecked()
+            };
+            let ch_len = ch.len_utf8();
+            if !f(ch) {
+                guard.deleted += ch_len;
+            } else if guard.deleted > 0 {
+                unsafe {
+                    ptr::copy(
+                        data.add(guard.idx),
+                        data.add(guard.idx - guard.deleted),
+                        ch_len,
+                    );
+                }
+            }
+            guard.idx += ch_len;
+        }
+    }
+
+    // The idx must be on a char boundary
+    pub fn insert_str(&mut self, idx: usize, string: &str) {
+        let str_len = string.len();
+        if str_len == 0 {
+            return;
+        }
+        let text = self.as_str();
+        let len = text.len();
+        let new_len = len + str_len;
+        unsafe {
+            if self.is_small() {
+                let last = if idx == len { string } else { text }.as_bytes();
+                if Self::fits_small(new_len, last[last.len() - 1]) {
+                    let data = self.small_mut_ptr();
+                    ptr::copy(data.add(idx), data.add(idx + str_len), len - idx);
+                    ptr::copy_nonoverlapping(string.as_ptr(), data.add(idx), str_len);
+                    self.set_small_len(new_len);
+                    return;
                 }
             }
+            if !self.owns_heap() {
+                self.splice_to_large(idx, idx, string);
+                return;
+            }
+            self.reserve(str_len);
+            // Medium or unique Large with enough capacity
+            let data = self.heap_text_ptr() as *mut u8;
+            ptr::copy(data.add(idx), data.add(idx + str_len), len - idx);
+            ptr::copy_nonoverlapping(string.as_ptr(), data.add(idx), str_len);
+            self.set_heap_len(new_len);
         }
     }
 
@@ -273,44 +2253,41 @@ impl StringInner {
 
         let len_ch = ch.len_utf8();
         let next = idx + len_ch;
+        // Static and shared Adopted, shared Large is copied without the char below
+        if !self.is_small() && !self.is_large() && !self.owns_heap() {
+            self.unshare();
+        }
         unsafe {
-            if self.is_large() {
-                let old_len = self.large.len & LARGE_MASK;
+            if self.is_medium() {
+                let data = self.heap_data();
+                let old_len = self.heap_len();
+                ptr::copy(data.add(next), data.add(idx), old_len - next);
+                self.set_heap_len(old_len - len_ch);
+            } else if self.is_large() {
+                let offset = self.large_offset();
+                let old_len = self.heap_len();
                 let new_len = old_len - len_ch;
-                match self.large.data.get_mut() {
-                    Some(old_data) => {
-                        ptr::copy(
-                            old_data.as_ptr().add(next),
-                            old_data.as_mut_ptr().add(idx),
-                            old_len - next,
-                        );
-                    }
-                    None => {
-                        let new_capacity = ArcVecU8::align_capacity(new_len);
-                        let mut new_data = ArcVecU8::with_capacity(new_capacity);
-                        new_data.extend_from(0, self.large.data.as_ptr(), idx);
-                        new_data.extend_from(
-                            idx,
-                            self.large.data.as_ptr().add(next),
-                            old_len - next,
-                        );
-                        let old_capacity = self.large.capacity;
-                        self.large.data.drop(old_capacity);
-                        self.large.data = new_data;
-                        self.large.capacity = new_capacity;
-                    }
+                let (data, alloc) = self.large_data_alloc_mut();
+                let text = data.as_mut_ptr().add(offset);
+                if data.get_mut().is_some() {
+                    ptr::copy(text.add(next), text.add(idx), old_len - next);
+                    self.set_heap_len(new_len);
+                } else {
+                    let new_capacity = align_capacity(new_len);
+                    let mut new_data = ArcVecU8::with_capacity(new_capacity, alloc);
+                    new_data.extend_from(0, text, idx);
+                    new_data.extend_from(idx, text.add(next), old_len - next);
+                    data.drop(alloc);
+                    self.set_large(new_data, 0, new_len);
                 }
-                self.large.len = new_len | LARGE_BIT;
             } else {
-                let old_len = self.small.len as usize;
-                ptr::copy(
-                    self.small.data.as_ptr().add(next),
-                    self.small.data.as_mut_ptr().add(idx),
-                    old_len - next,
-                );
-                self.small.len = (old_len - len_ch) as u8;
+                let old_len = self.small_len();
+                let data = self.small_mut_ptr();
+                ptr::copy(data.add(next), data.add(idx), old_len - next);
+                self.set_small_len(old_len - len_ch);
             }
         }
+        self.auto_demote();
         ch
     }
 }
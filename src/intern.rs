@@ -0,0 +1,143 @@
+//! Thread-safe interner: equal strings share one buffer and get one symbol id.
+
+use crate::inner::{Global, StringInner};
+use crate::{BasicFastString, FastString};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Id of the interned string, ids aren't reused after [Interner::purge].
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+/// Counters of the interner since its creation, except `entries`.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Stats {
+    /// Strings in the interner now.
+    pub entries: usize,
+    /// Calls of `intern`, `symbol` and `intern_symbol` which found the string,
+    /// `lookup` isn't counted.
+    pub hits: u64,
+    /// Calls of `intern`, `symbol` and `intern_symbol` which added the string.
+    pub misses: u64,
+    /// Strings removed by purge.
+    pub purged: u64,
+}
+
+#[derive(Default)]
+struct State {
+    symbols: HashMap<FastString, Symbol>,
+    // Holds the second reference to the shared buffer
+    strings: HashMap<Symbol, FastString>,
+    next: u32,
+    stats: Stats,
+}
+
+impl State {
+    fn intern(&mut self, string: &str) -> (Symbol, FastString) {
+        if let Some((interned, &symbol)) = self.symbols.get_key_value(string) {
+            self.stats.hits += 1;
+            return (symbol, interned.clone());
+        }
+        self.stats.misses += 1;
+        let symbol = Symbol(self.next);
+        self.next = self.next.checked_add(1).expect("too many interned strings");
+        // Even a short string gets the shared buffer, so purge sees its counter
+        let interned = BasicFastString(StringInner::from_shared_in(string, Global));
+        self.symbols.insert(interned.clone(), symbol);
+        self.strings.insert(symbol, interned.clone());
+        (symbol, interned)
+    }
+}
+
+/// Deduplicates strings: equal strings share the same buffer,
+/// even the short ones, which would be stored inline otherwise.
+#[derive(Default)]
+pub struct Interner {
+    state: Mutex<State>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state is consistent after any panic of the user code
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Returns the interned string equal to `string`, adds it if there is no such.
+    pub fn intern(&self, string: &str) -> FastString {
+        self.state().intern(string).1
+    }
+
+    /// Like [Self::intern], but returns the symbol of the string.
+    pub fn symbol(&self, string: &str) -> Symbol {
+        self.state().intern(string).0
+    }
+
+    /// Like [Self::intern], but returns both the symbol and the string.
+    pub fn intern_symbol(&self, string: &str) -> (Symbol, FastString) {
+        self.state().intern(string)
+    }
+
+    /// Returns the symbol of the interned string without adding it.
+    pub fn lookup(&self, string: &str) -> Option<Symbol> {
+        self.state().symbols.get(string).copied()
+    }
+
+    /// Returns the interned string of the symbol, None if it was purged.
+    pub fn resolve(&self, symbol: Symbol) -> Option<FastString> {
+        self.state().strings.get(&symbol).cloned()
+    }
+
+    /// Removes strings which aren't used outside of the interner, returns their number.
+    pub fn purge(&self) -> usize {
+        let mut state = self.state();
+        let unused: Vec<Symbol> = state
+            .strings
+            .iter()
+            .filter(|(_, string)| string.0.strong_count() == Some(2))
+            .map(|(&symbol, _)| symbol)
+            .collect();
+        for symbol in unused.iter() {
+            let string = state.strings.remove(symbol).unwrap();
+            state.symbols.remove(&string);
+        }
+        state.stats.purged += unused.len() as u64;
+        unused.len()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.state();
+        Stats {
+            entries: state.strings.len(),
+            ..state.stats
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state().strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The interner shared by the whole program.
+pub fn global() -> &'static Interner {
+    static GLOBAL: OnceLock<Interner> = OnceLock::new();
+    GLOBAL.get_or_init(Interner::new)
+}
+
+/// Interns the string in the [global] interner.
+pub fn intern(string: &str) -> FastString {
+    global().intern(string)
+}
@@ -1,14 +1,184 @@
 mod inner;
+pub mod intern;
 
-use inner::StringInner;
+pub use inner::{Allocator, Capacity, Global, Inline, RefCount, Representation, TryReserveError};
+use inner::{StringInner, WeakInner, SMALL_CAPACITY};
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::iter::{FromIterator, FusedIterator};
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::ptr::NonNull;
+use std::str::Chars;
+use std::sync::atomic::AtomicUsize;
 use std::{cmp::Ord, cmp::Ordering, fmt, hash};
 
-#[derive(Clone)]
-pub struct FastString(StringInner);
+/// String with the counter of the shared buffer chosen by `R`,
+/// the size of the struct (and inline capacity) chosen by `N`
+/// and the allocator of the shared buffer chosen by `A`,
+/// use one of [FastString] and [LocalFastString], or [FastStringN] and [LocalFastStringN].
+pub struct BasicFastString<R: RefCount, const N: usize = SMALL_CAPACITY, A: Allocator = Global>(
+    StringInner<R, N, A>,
+)
+where
+    Inline<N>: Capacity;
 
-impl FastString {
-    pub fn new() -> Self {
-        Self(StringInner::new())
+/// String which can be sent and shared between threads.
+pub type FastString = BasicFastString<AtomicUsize>;
+
+/// String for a single thread, clone and drop of the shared buffer don't use atomics.
+pub type LocalFastString = BasicFastString<Cell<usize>>;
+
+/// [FastString] with the inline capacity `N` and the allocator `A`: `FastStringN<64>`
+/// stores up to 64 bytes inline. `FastStringN<16>` (`FastStringN<8>` on 32-bit targets)
+/// has room only for the pointer and len on the heap: its substrings share the buffer
+/// only from its start, the String buffer taken by `from` is copied on mutation,
+/// and [MAX_LEN](BasicFastString::MAX_LEN) is smaller.
+/// `A` allocates the shared buffer and the header, the buffer taken from String
+/// stays in the global allocator, see [from_string_in](BasicFastString::from_string_in).
+pub type FastStringN<const N: usize, A = Global> = BasicFastString<AtomicUsize, N, A>;
+
+/// [LocalFastString] with the inline capacity `N` and the allocator `A`.
+pub type LocalFastStringN<const N: usize, A = Global> = BasicFastString<Cell<usize>, N, A>;
+
+/// Iterator over the chars removed by [BasicFastString::drain].
+pub struct Drain<'a, R: RefCount, const N: usize, A: Allocator>
+where
+    Inline<N>: Capacity,
+{
+    string: NonNull<BasicFastString<R, N, A>>,
+    start: usize,
+    end: usize,
+    chars: Chars<'a>,
+    _string: PhantomData<&'a mut BasicFastString<R, N, A>>,
+}
+
+/// Weak reference to the text of [BasicFastString], see [downgrade](BasicFastString::downgrade).
+pub struct BasicWeakFastString<R: RefCount, const N: usize = SMALL_CAPACITY, A: Allocator = Global>(
+    WeakInner<R, N, A>,
+)
+where
+    Inline<N>: Capacity;
+
+/// Weak reference to [FastString].
+pub type WeakFastString = BasicWeakFastString<AtomicUsize>;
+
+/// Weak reference to [LocalFastString].
+pub type LocalWeakFastString = BasicWeakFastString<Cell<usize>>;
+
+impl<R: RefCount, const N: usize, A: Allocator> BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    /// Longest string stored without allocation, it's also the size of the struct:
+    /// by default 24 bytes on 64-bit targets and 12 bytes on 32-bit targets.
+    /// A string of exactly this length ending with '\0' is still allocated.
+    pub const INLINE_CAPACITY: usize = N;
+
+    /// Longest possible string, operations exceeding it panic with "capacity overflow":
+    /// `usize::MAX >> 3`, 512 MiB on 32-bit targets.
+    /// With the inline capacity of two words it's `usize::MAX >> 5`, 128 MiB on 32-bit targets.
+    pub const MAX_LEN: usize = StringInner::<R, N, A>::MAX_LEN;
+
+    pub fn new_in(alloc: A) -> Self {
+        Self(StringInner::new_in(alloc))
+    }
+
+    /// Creates the empty string which holds `capacity` bytes without reallocation:
+    /// inline if they fit, otherwise in its own buffer allocated by `alloc`.
+    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
+        let mut string = Self::new_in(alloc);
+        string.reserve_exact(capacity);
+        string
+    }
+
+    /// Copies the string, the shared buffer is allocated by `alloc`.
+    pub fn from_str_in(string: &str, alloc: A) -> Self {
+        Self(StringInner::from_in(string, alloc))
+    }
+
+    /// Like [Self::from_str_in], but returns an error instead of panic or abort.
+    pub fn try_from_str_in(string: &str, alloc: A) -> Result<Self, TryReserveError> {
+        StringInner::try_from_in(string, alloc).map(Self)
+    }
+
+    /// Reuses the buffer of the String, which is freed by the global allocator.
+    /// While the string owns it, push, reserve and shrink reallocate it
+    /// by the global allocator too, so `alloc` doesn't see these bytes.
+    /// The first clone shares it: the header with the counters is allocated by `alloc`.
+    /// With the inline capacity of two words the header is allocated at once,
+    /// and the first mutation copies the text.
+    pub fn from_string_in(string: String, alloc: A) -> Self {
+        Self(StringInner::from_string_in(string, alloc))
+    }
+
+    /// Wraps a string literal without allocation, clone is just a copy.
+    /// The text is copied to the buffer allocated by `alloc` only on the first mutation.
+    pub fn from_static_in(string: &'static str, alloc: A) -> Self {
+        Self(StringInner::from_static_in(string, alloc))
+    }
+
+    #[inline(always)]
+    pub fn allocator(&self) -> &A {
+        self.0.allocator()
+    }
+
+    /// Hash of the text with random keys chosen once per process, the same for equal strings.
+    /// The shared buffer computes it once for all its strings, until it's mutated,
+    /// except with the `compact-header` feature. See [CachedHash].
+    pub fn hash_code(&self) -> u64 {
+        self.0.hash_code()
+    }
+
+    /// Where the text is stored now, for tests and debugging.
+    pub fn representation(&self) -> Representation {
+        self.0.representation()
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.representation() == Representation::Inline
+    }
+
+    /// Returns true if the buffer is shared with other strings or weak references,
+    /// so mutation copies it.
+    pub fn is_shared(&self) -> bool {
+        self.representation() == Representation::Shared
+    }
+
+    /// Number of strings sharing the buffer, 1 for inline, unique and static strings.
+    pub fn strong_count(&self) -> usize {
+        self.0.strong_count().unwrap_or(1)
+    }
+
+    /// Bytes which the string can hold without reallocation:
+    /// the inline capacity, the capacity of the heap buffer after the start of the text,
+    /// or the length of the static string.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Returns true if both strings point to the same text, like clones of each other.
+    /// Inline strings are equal by pointer only to themselves.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.as_ptr() == other.as_ptr() && self.len() == other.len()
+    }
+
+    /// Returns the weak reference to the shared buffer, which doesn't keep the text alive.
+    /// The buffer adopted from String is shared first, like by clone.
+    /// Inline and static strings are copied to the weak reference and always upgrade.
+    pub fn downgrade(&self) -> BasicWeakFastString<R, N, A> {
+        BasicWeakFastString(WeakInner::new(&self.0))
+    }
+
+    /// Converts to the string with other inline capacity.
+    /// The heap buffer is moved as is, the inline string is copied.
+    /// The inline capacity of two words has no room for the offset in the buffer,
+    /// so a substring which doesn't start the buffer is copied to or from it.
+    pub fn into_capacity<const M: usize>(self) -> BasicFastString<R, M, A>
+    where
+        Inline<M>: Capacity,
+    {
+        BasicFastString(self.0.into_capacity())
     }
 
     #[inline(always)]
@@ -16,6 +186,66 @@ impl FastString {
         self.0.as_str() // TODO len/is_empty here instead deref
     }
 
+    /// Returns the mutable text, copying it first if the buffer is shared or static,
+    /// so the following mutations don't copy. The full inline string moves to the heap,
+    /// since its last byte is the tag.
+    pub fn make_mut(&mut self) -> &mut str {
+        self.0.as_mut_str()
+    }
+
+    /// Like `String::as_mut_str`, the same as [make_mut](Self::make_mut).
+    #[inline(always)]
+    pub fn as_mut_str(&mut self) -> &mut str {
+        self.make_mut()
+    }
+
+    /// Returns the substring in the range, like `&self[range]`, without copy:
+    /// the result shares the buffer or points to the static string.
+    /// A short result is stored inline. A string converted from String shares
+    /// its buffer from the first substring or clone.
+    /// With the inline capacity of two words only a prefix shares the buffer.
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds or not on char boundaries.
+    pub fn slice<I: RangeBounds<usize>>(&self, range: I) -> Self {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Self(self.0.substr(&self.as_str()[range]))
+    }
+
+    // Byte bounds of the range, panics like the indexing of str
+    fn bounds<I: RangeBounds<usize>>(&self, range: I) -> (usize, usize) {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let text = &self.as_str()[range];
+        let start = text.as_ptr() as usize - self.as_ptr() as usize;
+        (start, start + text.len())
+    }
+
+    /// Splits the string at the byte index `mid`, like [str::split_at], see [Self::slice].
+    ///
+    /// # Panics
+    ///
+    /// If `mid` is out of bounds or not on a char boundary.
+    pub fn split_at(&self, mid: usize) -> (Self, Self) {
+        let (left, right) = self.as_str().split_at(mid);
+        (Self(self.0.substr(left)), Self(self.0.substr(right)))
+    }
+
+    /// Returns the substring given as `&str` inside this string, see [Self::slice].
+    /// Useful with the methods of `str`, like `split_whitespace` or `trim`.
+    ///
+    /// # Panics
+    ///
+    /// If `substr` isn't a part of this string.
+    pub fn substr(&self, substr: &str) -> Self {
+        let begin = self.as_ptr() as usize;
+        let start = (substr.as_ptr() as usize).wrapping_sub(begin);
+        if start > self.len() || substr.len() > self.len() - start {
+            panic!("substring isn't a part of the string");
+        }
+        Self(self.0.substr(substr))
+    }
+
     #[inline(always)]
     pub fn push(&mut self, ch: char) {
         let mut temp = [0u8; 4];
@@ -27,50 +257,404 @@ impl FastString {
         self.0.push_str(string);
     }
 
+    /// Like [Self::push_str], but returns an error instead of panic or abort.
+    /// The string isn't changed on failure.
+    #[inline(always)]
+    pub fn try_push_str(&mut self, string: &str) -> Result<(), TryReserveError> {
+        self.0.try_push_str(string)
+    }
+
+    // The byte length of the strings is known only while they are pushed, so the lower bound
+    // of their number is reserved, like for chars, then the capacity grows amortized
+    fn extend_strings<S: std::ops::Deref<Target = str>>(&mut self, iter: impl Iterator<Item = S>) {
+        self.reserve(iter.size_hint().0);
+        iter.for_each(|string| self.push_str(&string));
+    }
+
+    /// Reserves capacity for at least `additional` more bytes, so pushing them doesn't allocate.
+    /// The capacity may grow more to avoid frequent reallocations, like in `String`.
+    /// A shared or static string is copied to its own buffer, an inline string stays inline
+    /// if the bytes fit there.
+    ///
+    /// # Panics
+    ///
+    /// If the new capacity exceeds [Self::MAX_LEN].
+    pub fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional)
+    }
+
+    /// Like [Self::reserve], but doesn't grow the capacity more than needed.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        self.0.reserve_exact(additional)
+    }
+
+    /// Like [Self::reserve], but returns an error instead of panic or abort.
+    /// The string isn't changed on failure.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.0.try_reserve(additional)
+    }
+
+    /// Like [Self::reserve_exact], but returns an error instead of panic or abort.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.0.try_reserve_exact(additional)
+    }
+
+    /// Reduces the capacity as much as possible, see [Self::shrink_to].
+    pub fn shrink_to_fit(&mut self) {
+        self.0.shrink_to(0);
+    }
+
+    /// Reduces the capacity to at least `min_capacity` and the length.
+    /// The string moves back inline if it fits and `min_capacity` isn't greater than
+    /// [Self::INLINE_CAPACITY]. The shared buffer is copied only if it's bigger than needed,
+    /// like for a short slice of a long string. Static strings aren't changed.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.0.shrink_to(min_capacity);
+    }
+
     #[inline(always)]
     pub fn remove(&mut self, idx: usize) -> char {
         self.0.remove(idx)
     }
+
+    /// Shortens the string to `new_len` bytes, like `String::truncate`, nothing happens
+    /// if it's already shorter. The capacity isn't changed and the shared buffer isn't copied,
+    /// except with the `auto-demote` feature: the string which fits inline moves there
+    /// and releases the buffer.
+    ///
+    /// # Panics
+    ///
+    /// If `new_len` isn't on a char boundary.
+    pub fn truncate(&mut self, new_len: usize) {
+        if new_len < self.len() {
+            assert!(self.is_char_boundary(new_len));
+            self.0.truncate(new_len);
+        }
+    }
+
+    /// Removes the last char and returns it, or None if the string is empty.
+    pub fn pop(&mut self) -> Option<char> {
+        let ch = self.chars().next_back()?;
+        self.0.truncate(self.len() - ch.len_utf8());
+        Some(ch)
+    }
+
+    /// Removes all chars. The own buffer is kept like in `String`,
+    /// the shared or static one is released.
+    /// With the `auto-demote` feature the own buffer is released too, the string becomes inline.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Splits the string at the byte index `at`, returns the tail and keeps the head,
+    /// like `String::split_off`. The tail shares the buffer or is stored inline.
+    ///
+    /// # Panics
+    ///
+    /// If `at` is out of bounds or not on a char boundary.
+    pub fn split_off(&mut self, at: usize) -> Self {
+        assert!(self.is_char_boundary(at));
+        let tail = Self(self.0.substr(&self.as_str()[at..]));
+        self.0.truncate(at);
+        tail
+    }
+
+    /// Replaces the range with the string, like `String::replace_range`.
+    /// The own text is changed in place, the shared or static text is copied once,
+    /// or just becomes its substring if only its prefix or suffix is removed.
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds or not on char boundaries.
+    pub fn replace_range<I: RangeBounds<usize>>(&mut self, range: I, replace_with: &str) {
+        let (start, end) = self.bounds(range);
+        self.0.replace_range(start, end, replace_with);
+    }
+
+    /// Removes the range and returns its chars, like `String::drain`.
+    /// The string is changed when the iterator is dropped, see [Self::replace_range].
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds or not on char boundaries.
+    pub fn drain<I: RangeBounds<usize>>(&mut self, range: I) -> Drain<'_, R, N, A> {
+        let (start, end) = self.bounds(range);
+        let string = NonNull::from(self);
+        // The string isn't accessed through other references until the drop of Drain
+        let chars = unsafe { (*string.as_ptr()).as_str().get_unchecked(start..end) }.chars();
+        Drain {
+            string,
+            start,
+            end,
+            chars,
+            _string: PhantomData,
+        }
+    }
+
+    /// Keeps only the chars for which `f` returns true, like `String::retain`.
+    /// The own text is changed in place, the shared or static text is copied once before.
+    pub fn retain<F: FnMut(char) -> bool>(&mut self, f: F) {
+        self.0.retain(f);
+    }
+
+    /// Inserts the char at the byte index `idx`, like `String::insert`.
+    /// A unique buffer is shifted in place, a shared or static text is copied once.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is out of bounds or not on a char boundary.
+    pub fn insert(&mut self, idx: usize, ch: char) {
+        let mut temp = [0u8; 4];
+        self.insert_str(idx, ch.encode_utf8(&mut temp));
+    }
+
+    /// Inserts the string at the byte index `idx`, like `String::insert_str`, see [Self::insert].
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is out of bounds or not on a char boundary.
+    pub fn insert_str(&mut self, idx: usize, string: &str) {
+        assert!(self.is_char_boundary(idx));
+        self.0.insert_str(idx, string);
+    }
 }
 
-impl Default for FastString {
+impl<R: RefCount, const N: usize, A: Allocator + Default> BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    pub fn new() -> Self {
+        Self::new_in(A::default())
+    }
+
+    /// See [Self::with_capacity_in].
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_in(capacity, A::default())
+    }
+
+    /// Like `FastString::from`, but returns an error instead of panic or abort.
+    pub fn try_from_str(string: &str) -> Result<Self, TryReserveError> {
+        Self::try_from_str_in(string, A::default())
+    }
+
+    /// Converts a vector of bytes to a FastString, reusing its buffer.
+    pub fn from_utf8(vec: Vec<u8>) -> Result<Self, std::string::FromUtf8Error> {
+        String::from_utf8(vec).map(Self::from)
+    }
+
+    /// Wraps a string literal without allocation, clone is just a copy.
+    /// The text is copied to the heap only on the first mutation.
+    pub fn from_static(string: &'static str) -> Self {
+        Self::from_static_in(string, A::default())
+    }
+}
+
+impl<const N: usize, A: Allocator> From<FastStringN<N, A>> for LocalFastStringN<N, A>
+where
+    Inline<N>: Capacity,
+{
+    /// Reuses the buffer if it isn't shared, otherwise copies it.
+    fn from(string: FastStringN<N, A>) -> Self {
+        Self(string.0.into_counter())
+    }
+}
+
+impl<const N: usize, A: Allocator> From<LocalFastStringN<N, A>> for FastStringN<N, A>
+where
+    Inline<N>: Capacity,
+{
+    /// Reuses the buffer if it isn't shared, otherwise copies it.
+    fn from(string: LocalFastStringN<N, A>) -> Self {
+        Self(string.0.into_counter())
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Clone for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator + Default> Default for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<'a> From<&'a str> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator + Default> From<&str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn from(string: &str) -> Self {
-        Self(StringInner::from(string))
+        Self::from_str_in(string, A::default())
     }
 }
 
-impl<'a> From<&'a mut str> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator + Default> From<&mut str>
+    for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn from(string: &mut str) -> Self {
         Self::from(&*string)
     }
 }
 
-impl From<String> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator + Default> From<String> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn from(string: String) -> Self {
-        Self::from(string.as_str())
+        Self::from_string_in(string, A::default())
     }
 }
 
-impl From<FastString> for String {
-    fn from(string: FastString) -> Self {
-        Self::from(string.as_str())
+impl<R: RefCount, const N: usize, A: Allocator> From<BasicFastString<R, N, A>> for String
+where
+    Inline<N>: Capacity,
+{
+    fn from(string: BasicFastString<R, N, A>) -> Self {
+        string.0.into_string()
     }
 }
 
-impl From<char> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator + Default> From<char> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn from(ch: char) -> Self {
         let mut temp = [0u8; 4];
         Self::from(ch.encode_utf8(&mut temp))
     }
 }
 
-impl std::ops::Deref for FastString {
+// The lower bound of chars is the lower bound of bytes, a short result stays inline
+impl<R: RefCount, const N: usize, A: Allocator> Extend<char> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn extend<I: IntoIterator<Item = char>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        iter.for_each(|ch| self.push(ch));
+    }
+}
+
+impl<'a, R: RefCount, const N: usize, A: Allocator> Extend<&'a str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn extend<I: IntoIterator<Item = &'a str>>(&mut self, iter: I) {
+        self.extend_strings(iter.into_iter());
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Extend<String> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn extend<I: IntoIterator<Item = String>>(&mut self, iter: I) {
+        self.extend_strings(iter.into_iter());
+    }
+}
+
+impl<R: RefCount, const N: usize, const M: usize, A: Allocator, B: Allocator>
+    Extend<BasicFastString<R, M, B>> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+    Inline<M>: Capacity,
+{
+    fn extend<I: IntoIterator<Item = BasicFastString<R, M, B>>>(&mut self, iter: I) {
+        self.extend_strings(iter.into_iter());
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator + Default> FromIterator<char>
+    for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn from_iter<I: IntoIterator<Item = char>>(iter: I) -> Self {
+        let mut string = Self::new();
+        string.extend(iter);
+        string
+    }
+}
+
+impl<'a, R: RefCount, const N: usize, A: Allocator + Default> FromIterator<&'a str>
+    for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut string = Self::new();
+        string.extend(iter);
+        string
+    }
+}
+
+// The buffer of the first String is reused, like in String
+impl<R: RefCount, const N: usize, A: Allocator + Default> FromIterator<String>
+    for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        let mut iter = iter.into_iter();
+        let mut string = match iter.next() {
+            Some(first) => Self::from(first),
+            None => return Self::new(),
+        };
+        string.extend(iter);
+        string
+    }
+}
+
+impl<R: RefCount, const N: usize, const M: usize, A: Allocator + Default, B: Allocator>
+    FromIterator<BasicFastString<R, M, B>> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+    Inline<M>: Capacity,
+{
+    fn from_iter<I: IntoIterator<Item = BasicFastString<R, M, B>>>(iter: I) -> Self {
+        let mut string = Self::new();
+        string.extend(iter);
+        string
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> std::ops::Add<&str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    type Output = Self;
+
+    fn add(mut self, other: &str) -> Self {
+        self.push_str(other);
+        self
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> std::ops::AddAssign<&str>
+    for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn add_assign(&mut self, other: &str) {
+        self.push_str(other);
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> std::ops::Deref for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     type Target = str;
 
     fn deref(&self) -> &Self::Target {
@@ -78,88 +662,285 @@ impl std::ops::Deref for FastString {
     }
 }
 
-impl fmt::Debug for FastString {
+impl<R: RefCount, const N: usize, A: Allocator> std::ops::DerefMut for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.make_mut()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> fmt::Debug for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(self.as_str(), f)
     }
 }
 
-impl fmt::Display for FastString {
+impl<R: RefCount, const N: usize, A: Allocator> fmt::Display for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(self.as_str(), f)
     }
 }
 
-impl PartialEq<FastString> for FastString {
-    fn eq(&self, other: &FastString) -> bool {
-        self.as_str() == other.as_str()
+impl<R: RefCount, const N: usize, const M: usize, A: Allocator, B: Allocator>
+    PartialEq<BasicFastString<R, M, B>> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+    Inline<M>: Capacity,
+{
+    fn eq(&self, other: &BasicFastString<R, M, B>) -> bool {
+        self.0.eq(&other.0)
     }
 }
 
-impl Eq for FastString {}
+impl<R: RefCount, const N: usize, A: Allocator> Eq for BasicFastString<R, N, A> where
+    Inline<N>: Capacity
+{
+}
 
-impl PartialEq<str> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn eq(&self, other: &str) -> bool {
         self.as_str() == other
     }
 }
 
-impl PartialEq<FastString> for str {
-    fn eq(&self, other: &FastString) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<BasicFastString<R, N, A>> for str
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &BasicFastString<R, N, A>) -> bool {
         other == self
     }
 }
 
-impl<'a> PartialEq<&'a str> for FastString {
-    fn eq(&self, other: &&'a str) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<&str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &&str) -> bool {
         self == *other
     }
 }
 
-impl<'a> PartialEq<FastString> for &'a str {
-    fn eq(&self, other: &FastString) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<BasicFastString<R, N, A>> for &str
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &BasicFastString<R, N, A>) -> bool {
         other == *self
     }
 }
 
-impl PartialEq<String> for FastString {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<String> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn eq(&self, other: &String) -> bool {
         self.as_str() == other
     }
 }
 
-impl PartialEq<FastString> for String {
-    fn eq(&self, other: &FastString) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<BasicFastString<R, N, A>> for String
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &BasicFastString<R, N, A>) -> bool {
         other == self
     }
 }
 
-impl<'a> PartialEq<&'a String> for FastString {
-    fn eq(&self, other: &&'a String) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<&String> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &&String) -> bool {
         self == *other
     }
 }
 
-impl<'a> PartialEq<FastString> for &'a String {
-    fn eq(&self, other: &FastString) -> bool {
+impl<R: RefCount, const N: usize, A: Allocator> PartialEq<BasicFastString<R, N, A>> for &String
+where
+    Inline<N>: Capacity,
+{
+    fn eq(&self, other: &BasicFastString<R, N, A>) -> bool {
         *self == other
     }
 }
 
-impl Ord for FastString {
-    fn cmp(&self, other: &FastString) -> Ordering {
-        self.as_str().cmp(other.as_str())
+impl<R: RefCount, const N: usize, A: Allocator> Ord for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn cmp(&self, other: &BasicFastString<R, N, A>) -> Ordering {
+        self.0.cmp(&other.0)
     }
 }
 
-impl PartialOrd for FastString {
-    fn partial_cmp(&self, other: &FastString) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl<R: RefCount, const N: usize, const M: usize, A: Allocator, B: Allocator>
+    PartialOrd<BasicFastString<R, M, B>> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+    Inline<M>: Capacity,
+{
+    fn partial_cmp(&self, other: &BasicFastString<R, M, B>) -> Option<Ordering> {
+        Some(self.0.cmp(&other.0))
     }
 }
 
-impl hash::Hash for FastString {
+impl<R: RefCount, const N: usize, A: Allocator> hash::Hash for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
     fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
         self.as_str().hash(hasher)
     }
 }
+
+/// Key of hash containers hashed by [hash_code](BasicFastString::hash_code),
+/// so the lookup of a large shared key doesn't hash its text again.
+/// Unlike the string it can't be looked up by `&str`.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct CachedHash<S>(pub S);
+
+impl<R: RefCount, const N: usize, A: Allocator> hash::Hash for CachedHash<BasicFastString<R, N, A>>
+where
+    Inline<N>: Capacity,
+{
+    fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+        hasher.write_u64(self.0.hash_code())
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Borrow<str> for BasicFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> BasicWeakFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    /// Returns the string sharing the buffer, or None if all its strings were dropped.
+    pub fn upgrade(&self) -> Option<BasicFastString<R, N, A>> {
+        self.0.upgrade().map(BasicFastString)
+    }
+
+    /// Number of strings sharing the buffer, 0 after they were dropped,
+    /// 1 for the copied inline or static string.
+    pub fn strong_count(&self) -> usize {
+        self.0.strong_count().unwrap_or(1)
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Clone for BasicWeakFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn clone(&self) -> Self {
+        BasicWeakFastString(self.0.clone())
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> fmt::Debug for BasicWeakFastString<R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(Weak)")
+    }
+}
+
+// SAFETY: Drain is `&mut BasicFastString` with the chars borrowed from it,
+// the pointer is used only by drop, after the last use of the chars
+unsafe impl<R: RefCount, const N: usize, A: Allocator> Send for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+    BasicFastString<R, N, A>: Send,
+{
+}
+
+// SAFETY: `&Drain` only reads the removed text, like `&BasicFastString`
+unsafe impl<R: RefCount, const N: usize, A: Allocator> Sync for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+    BasicFastString<R, N, A>: Sync,
+{
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    /// The rest of the removed text.
+    pub fn as_str(&self) -> &str {
+        self.chars.as_str()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Drop for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn drop(&mut self) {
+        unsafe {
+            self.string
+                .as_mut()
+                .0
+                .replace_range(self.start, self.end, "");
+        }
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> Iterator for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    type Item = char;
+
+    #[inline]
+    fn next(&mut self) -> Option<char> {
+        self.chars.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chars.size_hint()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> DoubleEndedIterator for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    #[inline]
+    fn next_back(&mut self) -> Option<char> {
+        self.chars.next_back()
+    }
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> FusedIterator for Drain<'_, R, N, A> where
+    Inline<N>: Capacity
+{
+}
+
+impl<R: RefCount, const N: usize, A: Allocator> fmt::Debug for Drain<'_, R, N, A>
+where
+    Inline<N>: Capacity,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Drain").field(&self.as_str()).finish()
+    }
+}
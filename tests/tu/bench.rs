@@ -1,12 +1,16 @@
 use crate::is_same;
 use crate::random_string;
-use fast_string::FastString;
+use fast_string::{CachedHash, FastString, FastStringN};
 use rand::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Clone)]
 enum BenchType {
     Small,
     Medium,
@@ -15,7 +19,7 @@ enum BenchType {
 
 fn get_iter(bench_type: BenchType) -> usize {
     match bench_type {
-        BenchType::Small => 1000_000,
+        BenchType::Small => 1_000_000,
         BenchType::Medium => 100_000,
         BenchType::Large => 10_000,
     }
@@ -58,7 +62,7 @@ impl BlackBox {
 
     #[inline(never)]
     fn light_add(&mut self, s: &mut String, fs: &mut FastString) {
-        assert!(is_same(&s, &fs));
+        assert!(is_same(s, fs));
         self.sizes.push(s.len());
         self.sizes.push(fs.len());
     }
@@ -514,3 +518,177 @@ fn bench_remove_large() {
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn header_name() -> &'static str {
+    if cfg!(feature = "compact-header") {
+        "compact header"
+    } else {
+        "cache line header"
+    }
+}
+
+fn clone_in_threads(fast_strings: &[FastString], iter: usize) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for fs in fast_strings.iter() {
+            scope.spawn(move || {
+                for _ in 0..iter {
+                    black_box(fs.clone());
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+#[test]
+fn bench_clone_threads() {
+    const THREADS: usize = 4;
+    let iter = get_iter(BenchType::Small);
+    // Buffers allocated one after another, so the compact counters share the cache line
+    let strings: Vec<String> = (0..THREADS).map(|_| random_string(30)).collect();
+    let fast_strings: Vec<FastString> = strings
+        .iter()
+        .map(|s| FastString::from(s.as_str()))
+        .collect();
+
+    // Every thread clones its own string, without false sharing the time is the same
+    let one_time = clone_in_threads(&fast_strings[..1], iter);
+    let all_time = clone_in_threads(&fast_strings, iter);
+
+    for (s, fs) in strings.iter().zip(fast_strings.iter()) {
+        assert!(is_same(s, fs));
+    }
+    println!(
+        "bench_clone_threads for str with size 30 byte, {}",
+        header_name()
+    );
+    println!(
+        "{} threads {:0.2} % of the time 1 thread",
+        THREADS,
+        all_time.as_nanos() as f64 / one_time.as_nanos() as f64 * 100.0
+    );
+    println!("1 thread  time: {:?}", one_time);
+    println!("{} threads time: {:?}", THREADS, all_time);
+}
+
+struct Allocated {
+    bytes: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for Allocated {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.bytes.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[test]
+fn bench_memory_medium() {
+    static ALLOCATED: Allocated = Allocated {
+        bytes: AtomicUsize::new(0),
+    };
+    let iter = get_iter(BenchType::Medium);
+    let strings: Vec<String> = (0..iter).map(|_| random_string(30)).collect();
+    let fast_strings: Vec<FastStringN<24, &Allocated>> = strings
+        .iter()
+        .map(|s| FastStringN::from_str_in(s, &ALLOCATED))
+        .collect();
+    let bytes = ALLOCATED.bytes.load(Ordering::Relaxed);
+
+    for (s, fs) in strings.iter().zip(fast_strings.iter()) {
+        assert!(is_same(s, fs));
+    }
+    println!("bench_memory for str with size 30 byte, {}", header_name());
+    println!(
+        "FastString heap bytes per string: {:0.2}",
+        bytes as f64 / iter as f64
+    );
+    drop(fast_strings);
+    assert_eq!(ALLOCATED.bytes.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn bench_hash_lookup() {
+    let iter = get_iter(BenchType::Medium);
+    let strings: Vec<String> = (0..100).map(|_| random_string(1000)).collect();
+    let fast_strings: Vec<FastString> = strings
+        .iter()
+        .map(|s| FastString::from(s.as_str()))
+        .collect();
+    let s_map: std::collections::HashMap<String, usize> =
+        strings.iter().cloned().zip(0..).collect();
+    let fs_map: std::collections::HashMap<CachedHash<FastString>, usize> = fast_strings
+        .iter()
+        .map(|fs| CachedHash(fs.clone()))
+        .zip(0..)
+        .collect();
+
+    // The keys are looked up again and again, like repeated requests
+    let mut s_sum = 0;
+    let start = Instant::now();
+    for i in 0..iter {
+        s_sum += s_map[&strings[i % strings.len()]];
+    }
+    let s_time = start.elapsed();
+
+    let keys: Vec<CachedHash<FastString>> = fast_strings.iter().cloned().map(CachedHash).collect();
+    let mut fs_sum = 0;
+    let start = Instant::now();
+    for i in 0..iter {
+        fs_sum += fs_map[&keys[i % keys.len()]];
+    }
+    let fs_time = start.elapsed();
+
+    assert_eq!(black_box(s_sum), black_box(fs_sum));
+    print_bench_result(BenchType::Medium, "hash_lookup", s_time, fs_time);
+}
+
+fn bench_dedup(bench_type: BenchType, size: usize) {
+    let iter = get_iter(bench_type.clone());
+    let strings: Vec<String> = (0..100).map(|_| random_string(size)).collect();
+    let fast_strings: Vec<FastString> = strings
+        .iter()
+        .map(|s| FastString::from(s.as_str()))
+        .collect();
+    // Every string is repeated, like the values of a column with duplicates
+    let s_copies: Vec<String> = strings.clone();
+    let fs_copies: Vec<FastString> = fast_strings.clone();
+
+    let start = Instant::now();
+    let mut s_equal = 0;
+    for _ in 0..iter / strings.len() {
+        for (string, copy) in strings.iter().zip(s_copies.iter()) {
+            s_equal += (black_box(string) == black_box(copy)) as usize;
+        }
+    }
+    let s_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut fs_equal = 0;
+    for _ in 0..iter / fast_strings.len() {
+        for (string, copy) in fast_strings.iter().zip(fs_copies.iter()) {
+            fs_equal += (black_box(string) == black_box(copy)) as usize;
+        }
+    }
+    let fs_time = start.elapsed();
+
+    assert_eq!(s_equal, iter);
+    assert_eq!(fs_equal, iter);
+    print_bench_result(bench_type, "dedup", s_time, fs_time);
+}
+
+#[test]
+fn bench_dedup_small() {
+    bench_dedup(BenchType::Small, 20);
+}
+
+#[test]
+fn bench_dedup_medium() {
+    bench_dedup(BenchType::Medium, 1000);
+}
@@ -1,4 +1,4 @@
-use fast_string::FastString;
+use fast_string::{Allocator, BasicFastString, Capacity, Inline, RefCount};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::iter;
@@ -15,6 +15,12 @@ fn random_string(n: usize) -> String {
         .collect()
 }
 
-fn is_same(s: &String, fs: &FastString) -> bool {
+fn is_same<R: RefCount, const N: usize, A: Allocator>(
+    s: &String,
+    fs: &BasicFastString<R, N, A>,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
     s.is_empty() == fs.is_empty() && s.len() == fs.len() && s == fs
 }
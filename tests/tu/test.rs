@@ -1,23 +1,52 @@
 use crate::is_same;
 use crate::random_string;
-use fast_string::FastString;
+use fast_string::intern::{self, Interner, Stats};
+use fast_string::{
+    Allocator, BasicFastString, CachedHash, Capacity, Drain, FastString, FastStringN, Global,
+    Inline, LocalFastString, LocalFastStringN, RefCount, Representation, TryReserveError,
+};
 use quickcheck::{Arbitrary, Gen};
 use quickcheck_macros::quickcheck;
 use rand::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
 
-fn push_command(s: &mut String, fs: &mut FastString, ch: char) -> bool {
+fn push_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    ch: char,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
     s.push(ch);
     fs.push(ch);
     is_same(s, fs)
 }
 
-fn push_str_command(s: &mut String, fs: &mut FastString, add: &str) -> bool {
+fn push_str_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    add: &str,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
     s.push_str(add);
     fs.push_str(add);
     is_same(s, fs)
 }
 
-fn remove_command(s: &mut String, fs: &mut FastString, idx: usize) -> bool {
+fn remove_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    idx: usize,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
     if s.is_empty() {
         return true;
     }
@@ -30,17 +59,146 @@ fn remove_command(s: &mut String, fs: &mut FastString, idx: usize) -> bool {
     is_same(s, fs)
 }
 
+fn insert_str_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    idx: usize,
+    add: &str,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
+    let index = idx % (s.len() + 1);
+    if !s.is_char_boundary(index) {
+        return true;
+    }
+    s.insert_str(index, add);
+    fs.insert_str(index, add);
+    is_same(s, fs)
+}
+
+fn replace_range_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    start: usize,
+    end: usize,
+    add: &str,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
+    let (mut start, mut end) = (start % (s.len() + 1), end % (s.len() + 1));
+    if start > end {
+        std::mem::swap(&mut start, &mut end);
+    }
+    if !s.is_char_boundary(start) || !s.is_char_boundary(end) {
+        return true;
+    }
+    if add.is_empty() {
+        let drained: String = s.drain(start..end).collect();
+        let fs_drained: String = fs.drain(start..end).rev().collect();
+        drained.chars().rev().eq(fs_drained.chars()) && is_same(s, fs)
+    } else {
+        s.replace_range(start..end, add);
+        fs.replace_range(start..end, add);
+        is_same(s, fs)
+    }
+}
+
+fn retain_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    modulo: u32,
+) -> bool
+where
+    Inline<N>: Capacity,
+{
+    let modulo = modulo % 4 + 2;
+    s.retain(|ch| !(ch as u32).is_multiple_of(modulo));
+    fs.retain(|ch| !(ch as u32).is_multiple_of(modulo));
+    is_same(s, fs)
+}
+
+// The tail is returned to keep the buffer shared and check it later
+fn split_off_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    at: usize,
+) -> Option<(String, BasicFastString<R, N, A>)>
+where
+    Inline<N>: Capacity,
+{
+    let at = at % (s.len() + 1);
+    if !s.is_char_boundary(at) {
+        return None;
+    }
+    let tail = s.split_off(at);
+    let fs_tail = fs.split_off(at);
+    assert!(is_same(&tail, &fs_tail));
+    Some((tail, fs_tail))
+}
+
+// Replaces the strings with their substrings, the old strings are returned to keep the buffer
+// shared and check it later
+fn slice_command<R: RefCount, const N: usize, A: Allocator>(
+    s: &mut String,
+    fs: &mut BasicFastString<R, N, A>,
+    start: usize,
+    end: usize,
+) -> Option<(String, BasicFastString<R, N, A>)>
+where
+    Inline<N>: Capacity,
+{
+    let (mut start, mut end) = (start % (s.len() + 1), end % (s.len() + 1));
+    if start > end {
+        std::mem::swap(&mut start, &mut end);
+    }
+    if !s.is_char_boundary(start) || !s.is_char_boundary(end) {
+        return None;
+    }
+    let old = std::mem::replace(s, String::from(&s[start..end]));
+    Some((old, std::mem::replace(fs, fs.slice(start..end))))
+}
+
 #[derive(Clone, Debug)]
 enum Command {
-    Push { ch: char },
-    PushStr { add: String },
-    Remove { idx: usize },
+    Push {
+        ch: char,
+    },
+    PushStr {
+        add: String,
+    },
+    Remove {
+        idx: usize,
+    },
     Clone,
+    Slice {
+        start: usize,
+        end: usize,
+    },
+    Insert {
+        idx: usize,
+        add: String,
+    },
+    Pop,
+    SplitOff {
+        at: usize,
+    },
+    ReplaceRange {
+        start: usize,
+        end: usize,
+        add: String,
+    },
+    Retain {
+        modulo: u32,
+    },
+    FromString,
+    DropClones,
 }
 
 impl Arbitrary for Command {
     fn arbitrary(g: &mut Gen) -> Command {
-        match g.choose(&[0, 1, 2, 3]) {
+        match g.choose(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]) {
             Some(0) => Command::Push {
                 ch: char::arbitrary(g),
             },
@@ -51,10 +209,29 @@ impl Arbitrary for Command {
                 idx: usize::arbitrary(g),
             },
             Some(3) => Command::Clone {},
-            _ => {
-                assert!(false);
-                Command::Clone {}
-            }
+            Some(4) => Command::Slice {
+                start: usize::arbitrary(g),
+                end: usize::arbitrary(g),
+            },
+            Some(5) => Command::Insert {
+                idx: usize::arbitrary(g),
+                add: String::arbitrary(g),
+            },
+            Some(6) => Command::Pop,
+            Some(7) => Command::SplitOff {
+                at: usize::arbitrary(g),
+            },
+            Some(8) => Command::ReplaceRange {
+                start: usize::arbitrary(g),
+                end: usize::arbitrary(g),
+                add: String::arbitrary(g),
+            },
+            Some(9) => Command::Retain {
+                modulo: u32::arbitrary(g),
+            },
+            Some(10) => Command::FromString,
+            Some(11) => Command::DropClones,
+            _ => unreachable!(),
         }
     }
 }
@@ -80,28 +257,68 @@ fn test_remove_prop(mut string: String, index: usize) -> bool {
 }
 
 #[quickcheck]
-fn test_all_prop(mut string: String, commands: Vec<Command>) -> bool {
+fn test_insert_str_prop(mut string: String, index: usize, add: String) -> bool {
     let mut fast_string = FastString::from(string.as_str());
+    insert_str_command(&mut string, &mut fast_string, index, add.as_str())
+}
+
+fn all_commands<R: RefCount, const N: usize, A: Allocator>(
+    string: &mut String,
+    fast_string: &mut BasicFastString<R, N, A>,
+    commands: &[Command],
+) -> bool
+where
+    Inline<N>: Capacity,
+{
+    // Clones, slices and tails sharing the buffer, with their expected text,
+    // which the mutations of the string mustn't change
     let mut clones = Vec::new();
     for command in commands.iter() {
         let result = match command {
-            Command::Push { ch } => push_command(&mut string, &mut fast_string, *ch),
-            Command::PushStr { add } => {
-                push_str_command(&mut string, &mut fast_string, add.as_str())
-            }
-            Command::Remove { idx } => remove_command(&mut string, &mut fast_string, *idx),
+            Command::Push { ch } => push_command(string, fast_string, *ch),
+            Command::PushStr { add } => push_str_command(string, fast_string, add.as_str()),
+            Command::Remove { idx } => remove_command(string, fast_string, *idx),
             Command::Clone => {
-                clones.push(fast_string.clone());
-                is_same(&string, &fast_string)
+                clones.push((string.clone(), fast_string.clone()));
+                is_same(string, fast_string)
+            }
+            Command::Slice { start, end } => {
+                clones.extend(slice_command(string, fast_string, *start, *end));
+                is_same(string, fast_string)
+            }
+            Command::Insert { idx, add } => insert_str_command(string, fast_string, *idx, add),
+            Command::Pop => string.pop() == fast_string.pop() && is_same(string, fast_string),
+            Command::SplitOff { at } => {
+                clones.extend(split_off_command(string, fast_string, *at));
+                is_same(string, fast_string)
+            }
+            Command::ReplaceRange { start, end, add } => {
+                replace_range_command(string, fast_string, *start, *end, add)
+            }
+            Command::Retain { modulo } => retain_command(string, fast_string, *modulo),
+            Command::FromString => {
+                let alloc = fast_string.allocator().clone();
+                *fast_string = BasicFastString::from_string_in(fast_string.to_string(), alloc);
+                is_same(string, fast_string)
+            }
+            Command::DropClones => {
+                clones.clear();
+                is_same(string, fast_string)
             }
         };
-        if !result {
+        if !result || !clones.iter().all(|(s, fs)| is_same(s, fs)) {
             return false;
         }
     }
     true
 }
 
+#[quickcheck]
+fn test_all_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = FastString::from(string.as_str());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 #[test]
@@ -176,3 +393,1476 @@ fn test_clone() {
         }
     }
 }
+
+#[test]
+fn test_static() {
+    const TEXT: &str = "static string which is too long for the small buffer";
+    let mut s = String::from(TEXT);
+    let mut fs = FastString::from_static(TEXT);
+    let fs_clone = fs.clone();
+    assert!(is_same(&s, &fs));
+    assert!(is_same(&s, &fs_clone));
+    assert_eq!(fs.as_ptr(), TEXT.as_ptr());
+    assert_eq!(fs_clone.as_ptr(), TEXT.as_ptr());
+
+    assert!(push_str_command(&mut s, &mut fs, "!"));
+    assert_ne!(fs.as_ptr(), TEXT.as_ptr());
+    assert_eq!(fs_clone, TEXT);
+
+    let mut fs = fs_clone.clone();
+    let mut s = String::from(TEXT);
+    assert!(remove_command(&mut s, &mut fs, 0));
+    assert_eq!(fs_clone, TEXT);
+
+    assert!(is_same(
+        &String::from("short"),
+        &FastString::from_static("short")
+    ));
+}
+
+#[quickcheck]
+fn test_static_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = FastString::from_static(Box::leak(string.clone().into_boxed_str()));
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+#[test]
+fn test_from_string() {
+    for _ in 0..1000 {
+        let x: u8 = random();
+        let s = random_string(x as usize);
+        let mut fs = FastString::from(s.clone());
+        assert!(is_same(&s, &fs));
+        let fs_clone = fs.clone();
+        fs.push('!');
+        assert!(is_same(&s, &fs_clone));
+        let mut back = String::from(fs_clone);
+        assert_eq!(back, s);
+        back.push('!');
+        assert!(is_same(&back, &fs));
+    }
+
+    let s = random_string(100);
+    let ptr = s.as_ptr();
+    let fs = FastString::from(s);
+    assert_eq!(fs.as_ptr(), ptr);
+    let s = String::from(fs);
+    assert_eq!(s.as_ptr(), ptr);
+
+    let fs = FastString::from_utf8(s.clone().into_bytes()).unwrap();
+    assert!(is_same(&s, &fs));
+    assert!(FastString::from_utf8(vec![0xC0]).is_err());
+}
+
+#[test]
+fn test_clone_from_string() {
+    let s = random_string(100);
+    let fs = FastString::from(s.clone());
+    let ptr = fs.as_ptr();
+    assert_eq!(fs.representation(), Representation::Unique);
+    // The first clone shares the buffer of String
+    let mut clones = vec![fs.clone(); 10];
+    assert_eq!(fs.as_ptr(), ptr);
+    assert_eq!(fs.strong_count(), 11);
+    assert_eq!(fs.representation(), Representation::Shared);
+    assert!(clones.iter().all(|clone| clone.ptr_eq(&fs)));
+    assert_eq!(fs.capacity(), s.capacity());
+
+    // The shared text is copied before mutation, the clones keep it
+    let mut changed = fs.clone();
+    changed.push('!');
+    assert_ne!(changed.as_ptr(), ptr);
+    assert!(clones.iter().all(|clone| is_same(&s, clone)));
+    drop(fs);
+
+    // The last owner gets the buffer back
+    let mut last = clones.pop().unwrap();
+    drop(clones);
+    assert_eq!(last.representation(), Representation::Unique);
+    last.make_ascii_uppercase();
+    assert_eq!(last.as_ptr(), ptr);
+    let string = String::from(last);
+    assert_eq!(string.as_ptr(), ptr);
+    assert_eq!(string, s.to_ascii_uppercase());
+
+    // Clones from other threads share one buffer
+    let fs = FastString::from(s.clone());
+    let clones: Vec<FastString> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4).map(|_| scope.spawn(|| fs.clone())).collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    assert!(clones.iter().all(|clone| clone.ptr_eq(&fs)));
+    assert_eq!(fs.strong_count(), 5);
+    drop(fs);
+    assert!(clones.iter().all(|clone| is_same(&s, clone)));
+
+    // The local string and other counters
+    let local = LocalFastString::from(s.clone());
+    let clone = local.clone();
+    assert!(clone.ptr_eq(&local));
+    drop(local);
+    let fs: FastString = clone.into();
+    assert!(is_same(&s, &fs));
+}
+
+#[quickcheck]
+fn test_string_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = FastString::from(string.clone());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+fn is_inline<R: RefCount, const N: usize, A: Allocator>(fs: &BasicFastString<R, N, A>) -> bool
+where
+    Inline<N>: Capacity,
+{
+    let begin = fs as *const BasicFastString<R, N, A> as usize;
+    let end = begin + N;
+    (begin..end).contains(&(fs.as_ptr() as usize))
+}
+
+fn check_inline_capacity<const N: usize>()
+where
+    Inline<N>: Capacity,
+{
+    assert_eq!(FastStringN::<N>::INLINE_CAPACITY, N);
+    assert_eq!(std::mem::size_of::<FastStringN<N>>(), N);
+
+    for len in 0..=2 * N {
+        let mut s = random_string(len);
+        let fs = FastStringN::<N>::from(s.as_str());
+        assert!(is_same(&s, &fs));
+        assert_eq!(is_inline(&fs), len <= N);
+        if len < N {
+            let mut fs = fs;
+            assert!(push_command(&mut s, &mut fs, 'x'));
+            assert!(is_inline(&fs));
+            assert!(remove_command(&mut s, &mut fs, 0));
+        }
+    }
+
+    let mut s = random_string(N - 1);
+    s.push('\0');
+    let fs = FastStringN::<N>::from(s.as_str());
+    assert!(is_same(&s, &fs));
+    assert!(!is_inline(&fs));
+
+    let mut s = random_string(N - 2);
+    let mut fs = FastStringN::<N>::from(s.as_str());
+    assert!(push_str_command(&mut s, &mut fs, "й"));
+    assert!(is_inline(&fs));
+    assert!(remove_command(&mut s, &mut fs, N - 2));
+    assert!(push_command(&mut s, &mut fs, 'x'));
+    assert!(push_command(&mut s, &mut fs, '\0'));
+    assert!(!is_inline(&fs));
+}
+
+#[test]
+fn test_inline_capacity() {
+    #[cfg(target_pointer_width = "64")]
+    assert_eq!(FastString::INLINE_CAPACITY, 24);
+    #[cfg(target_pointer_width = "32")]
+    assert_eq!(FastString::INLINE_CAPACITY, 12);
+    assert_eq!(FastString::MAX_LEN, usize::MAX >> 3);
+    assert_eq!(FastStringN::<COMPACT>::MAX_LEN, usize::MAX >> 5);
+
+    check_inline_capacity::<COMPACT>();
+    check_inline_capacity::<{ FastString::INLINE_CAPACITY }>();
+    check_inline_capacity::<32>();
+    check_inline_capacity::<40>();
+    check_inline_capacity::<64>();
+    #[cfg(target_pointer_width = "32")]
+    check_inline_capacity::<100>();
+    check_inline_capacity::<128>();
+}
+
+#[test]
+fn test_other_capacity() {
+    let s = random_string(50);
+    let small = FastString::from(&s[..10]);
+    let large: FastStringN<64> = FastStringN::from(s.as_str());
+    assert!(is_inline(&large));
+    assert!(is_same(&s, &large));
+    assert!(small != large);
+    assert!(small < large);
+
+    let moved: FastString = large.clone().into_capacity();
+    assert!(!is_inline(&moved));
+    assert!(moved == large);
+    let ptr = moved.as_ptr();
+    let mut back: FastStringN<32> = moved.into_capacity();
+    assert_eq!(back.as_ptr(), ptr);
+    back.push_str(&s[..10]);
+    let mut expected = s.clone();
+    expected.push_str(&s[..10]);
+    assert!(is_same(&expected, &back));
+}
+
+// The inline capacity of two words, which has no room for the offset and capacity
+const COMPACT: usize = 2 * std::mem::size_of::<usize>();
+
+#[test]
+fn test_compact() {
+    let s = random_string(50);
+    let fs: FastStringN<COMPACT> = FastStringN::from(s.clone());
+    let ptr = fs.as_ptr();
+    assert!(is_same(&s, &fs));
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert_eq!(fs.capacity(), 50);
+
+    // The String buffer is shared by clones and prefixes, other substrings are copied
+    let clone = fs.clone();
+    let prefix = fs.slice(..40);
+    let suffix = fs.slice(10..);
+    assert_eq!(clone.as_ptr(), ptr);
+    assert_eq!(prefix.as_ptr(), ptr);
+    assert_ne!(suffix.as_ptr(), ptr.wrapping_add(10));
+    assert_eq!(fs.strong_count(), 3);
+    assert!(is_same(&s[..40].to_string(), &prefix));
+    assert!(is_same(&s[10..].to_string(), &suffix));
+    assert_eq!(suffix.strong_count(), 1);
+    assert_eq!(prefix.hash_code(), FastString::from(&s[..40]).hash_code());
+
+    let weak = fs.downgrade();
+    drop(clone);
+    drop(prefix);
+    assert!(fs.is_shared());
+    assert_eq!(weak.upgrade().unwrap(), fs);
+
+    // The unique String buffer goes back as is, the shared one is copied
+    let back = String::from(fs.clone());
+    assert_ne!(back.as_ptr(), ptr);
+    drop(weak);
+    let back = String::from(fs);
+    assert_eq!(back.as_ptr(), ptr);
+
+    // Mutation copies the String buffer
+    let mut fs: FastStringN<COMPACT> = FastStringN::from(back);
+    fs.push_str("x");
+    assert_ne!(fs.as_ptr(), ptr);
+    assert_eq!(fs.representation(), Representation::Unique);
+    let mut expected = s.clone();
+    expected.push('x');
+    assert!(is_same(&expected, &fs));
+
+    // Large is mutated in place
+    let ptr = fs.as_ptr();
+    fs.truncate(30);
+    fs.push_str("y");
+    fs.remove(0);
+    assert_eq!(fs.as_ptr(), ptr);
+    let mut expected = String::from(&s[1..30]);
+    expected.push('y');
+    assert!(is_same(&expected, &fs));
+
+    let fs: FastStringN<COMPACT> =
+        FastStringN::from_static("a static string longer than two words");
+    assert_eq!(fs.representation(), Representation::Static);
+    assert_eq!(fs.slice(5..).representation(), Representation::Static);
+}
+
+#[test]
+fn test_compact_into_capacity() {
+    let s = random_string(50);
+
+    // Large and Adopted from the start of the buffer move, Medium gets the header
+    let fs = FastString::from(s.as_str());
+    let ptr = fs.as_ptr();
+    let compact: FastStringN<COMPACT> = fs.into_capacity();
+    assert_eq!(compact.as_ptr(), ptr);
+    let back: FastStringN<64> = compact.into_capacity();
+    assert_eq!(back.as_ptr(), ptr);
+    assert!(is_same(&s, &back));
+
+    let fs = FastString::from(s.clone());
+    let ptr = fs.as_ptr();
+    let slice = fs.slice(..30);
+    let compact: FastStringN<COMPACT> = slice.into_capacity();
+    assert_eq!(compact.as_ptr(), ptr);
+    assert_eq!(fs.strong_count(), 2);
+    let back: FastString = compact.into_capacity();
+    assert_eq!(back.as_ptr(), ptr);
+    assert!(is_same(&s[..30].to_string(), &back));
+    drop(back);
+
+    let compact: FastStringN<COMPACT> = fs.into_capacity();
+    assert_eq!(compact.as_ptr(), ptr);
+    assert_eq!(compact.strong_count(), 1);
+    let back = String::from(compact);
+    assert_eq!(back.as_ptr(), ptr);
+
+    let fs = FastString::from(s.clone());
+    assert_eq!(fs.representation(), Representation::Unique);
+    let ptr = fs.as_ptr();
+    let compact: FastStringN<COMPACT> = fs.into_capacity();
+    assert_eq!(compact.as_ptr(), ptr);
+    assert!(is_same(&s, &compact));
+
+    // Substrings after the start are copied
+    let fs = FastString::from(s.as_str());
+    let compact: FastStringN<COMPACT> = fs.slice(10..).into_capacity();
+    assert_ne!(compact.as_ptr(), fs.as_ptr().wrapping_add(10));
+    assert!(is_same(&s[10..].to_string(), &compact));
+    assert_eq!(fs.strong_count(), 1);
+
+    let fs: FastStringN<COMPACT> =
+        FastStringN::from_static("a static string longer than two words");
+    let back: FastString = fs.clone().into_capacity();
+    assert_eq!(back.representation(), Representation::Static);
+    assert_eq!(back.as_ptr(), fs.as_ptr());
+}
+
+#[test]
+#[cfg(target_pointer_width = "32")]
+fn test_compact_into_capacity_huge() {
+    // The capacity doesn't fit Medium of the wider layout, so the String buffer stays Adopted
+    let string = String::with_capacity(1 << 30) + "123456789";
+    let mut fs: FastString = FastStringN::<COMPACT>::from(string).into_capacity();
+    fs.push('!');
+    assert_eq!(fs, "123456789!");
+}
+
+#[quickcheck]
+fn test_compact_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = FastStringN::<COMPACT>::from(string.as_str());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+#[quickcheck]
+fn test_compact_string_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = LocalFastStringN::<COMPACT>::from(string.clone());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+#[test]
+fn test_option_size() {
+    use std::mem::size_of;
+    assert_eq!(size_of::<Option<FastString>>(), size_of::<FastString>());
+    assert_eq!(size_of::<FastString>(), 3 * size_of::<usize>());
+    assert_eq!(size_of::<Option<LocalFastStringN<64>>>(), 64);
+
+    let strings: [Option<FastString>; 3] = [
+        Some(FastString::new()),
+        None,
+        Some(FastString::from(random_string(100).as_str())),
+    ];
+    assert!(strings[0].as_ref().unwrap().is_empty());
+    assert!(strings[1].is_none());
+    assert_eq!(strings[2].as_ref().unwrap().len(), 100);
+}
+
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FastString>();
+    assert_send_sync::<Drain<'static, AtomicUsize, 24, Global>>();
+
+    const THREADS: usize = 8;
+    const ITERATIONS: usize = 10_000;
+    let s = random_string(1000);
+    let fs = FastString::from(s.as_str());
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let fs = fs.clone();
+            let s = s.clone();
+            std::thread::spawn(move || {
+                let mut clones = Vec::new();
+                for j in 0..ITERATIONS {
+                    clones.push(fs.clone());
+                    if j % 3 == 0 {
+                        clones.swap_remove(j % clones.len());
+                    }
+                }
+                let mut own = clones.pop().unwrap();
+                let mut expected = s.clone();
+                expected.push_str(&i.to_string());
+                own.push_str(&i.to_string());
+                assert!(is_same(&expected, &own));
+                assert!(clones.iter().all(|clone| is_same(&s, clone)));
+                clones
+            })
+        })
+        .collect();
+    let clones: Vec<FastString> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    drop(fs);
+    assert!(clones.iter().all(|clone| is_same(&s, clone)));
+}
+
+#[quickcheck]
+fn test_local_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = LocalFastString::from(string.as_str());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+#[quickcheck]
+fn test_capacity_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let mut fast_string = FastStringN::<64>::from(string.as_str());
+    all_commands(&mut string, &mut fast_string, &commands)
+}
+
+#[test]
+fn test_local_conversion() {
+    let s = random_string(100);
+    let fs = FastString::from(s.as_str());
+    let ptr = fs.as_ptr();
+    let mut local = LocalFastString::from(fs);
+    assert!(is_same(&s, &local));
+    assert_eq!(local.as_ptr(), ptr);
+
+    let local_clone = local.clone();
+    local.push('!');
+    assert!(is_same(&s, &local_clone));
+    let fs = FastString::from(local_clone.clone());
+    assert!(is_same(&s, &fs));
+    assert_ne!(fs.as_ptr(), local_clone.as_ptr());
+
+    drop(local_clone);
+    let ptr = local.as_ptr();
+    let fs = FastString::from(local);
+    assert_eq!(fs.as_ptr(), ptr);
+    let fs = std::thread::spawn(move || fs).join().unwrap();
+    assert_eq!(fs.len(), s.len() + 1);
+
+    for len in [0, 10, 100] {
+        let s = random_string(len);
+        let local = LocalFastString::from(FastString::from(s.clone()));
+        assert!(is_same(&s, &local));
+        let fs = FastString::from(LocalFastString::from_static(Box::leak(
+            s.clone().into_boxed_str(),
+        )));
+        assert!(is_same(&s, &fs));
+    }
+}
+
+struct Tracking {
+    live: AtomicIsize,
+}
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.live.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Counting(Rc<Cell<isize>>);
+
+unsafe impl Allocator for Counting {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        self.0.set(self.0.get() + 1);
+        System.alloc(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        self.0.set(self.0.get() - 1);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[test]
+fn test_allocator() {
+    use std::mem::size_of;
+    assert_eq!(
+        size_of::<FastStringN<24, Global>>(),
+        size_of::<FastString>()
+    );
+    assert_eq!(
+        size_of::<Option<FastStringN<32, &Tracking>>>(),
+        32 + size_of::<usize>()
+    );
+
+    static TRACKING: Tracking = Tracking {
+        live: AtomicIsize::new(0),
+    };
+    let s = random_string(100);
+    let mut fs: FastStringN<24, &Tracking> = FastStringN::from_str_in(&s, &TRACKING);
+    assert_eq!(TRACKING.live.load(Ordering::Relaxed), 1);
+    let clone = fs.clone();
+    fs.push('!');
+    assert_eq!(TRACKING.live.load(Ordering::Relaxed), 2);
+    let local = LocalFastStringN::from(clone);
+    assert!(is_same(&s, &local));
+    drop(local);
+    assert!(std::ptr::eq(*fs.allocator(), &TRACKING));
+    let fs = std::thread::spawn(move || fs.into_capacity::<64>())
+        .join()
+        .unwrap();
+    assert_eq!(TRACKING.live.load(Ordering::Relaxed), 1);
+    drop(fs);
+    assert_eq!(TRACKING.live.load(Ordering::Relaxed), 0);
+
+    let counting = Counting::default();
+    let live = counting.0.clone();
+    let mut fs = LocalFastStringN::<24, Counting>::from_static_in(
+        "static string longer than inline",
+        counting,
+    );
+    let mut s = String::from(fs.as_str());
+    assert_eq!(live.get(), 0);
+    assert!(push_command(&mut s, &mut fs, 'x'));
+    assert_eq!(live.get(), 1);
+    let clones: Vec<_> = (0..10).map(|_| fs.clone()).collect();
+    assert!(remove_command(&mut s, &mut fs, 0));
+    assert_eq!(live.get(), 2);
+    drop(clones);
+    assert_eq!(live.get(), 1);
+
+    let medium =
+        LocalFastStringN::<24, _>::from_string_in(random_string(100), fs.allocator().clone());
+    let clone = medium.clone();
+    assert_eq!(live.get(), 2);
+    drop((fs, medium, clone));
+    assert_eq!(live.get(), 0);
+    assert_eq!(Rc::strong_count(&live), 1);
+}
+
+#[quickcheck]
+fn test_allocator_prop(mut string: String, commands: Vec<Command>) -> bool {
+    let counting = Counting::default();
+    let live = counting.0.clone();
+    let mut fast_string = LocalFastStringN::<32, Counting>::from_str_in(&string, counting);
+    let result = all_commands(&mut string, &mut fast_string, &commands);
+    drop(fast_string);
+    result && live.get() == 0 && Rc::strong_count(&live) == 1
+}
+
+// Fails when the allocations run out
+#[derive(Clone)]
+struct Limited(Rc<Cell<usize>>);
+
+unsafe impl Allocator for Limited {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        if self.0.get() == 0 {
+            return std::ptr::null_mut();
+        }
+        self.0.set(self.0.get() - 1);
+        System.alloc(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[test]
+fn test_try_reserve() {
+    let left = Rc::new(Cell::new(0));
+    let limited = Limited(left.clone());
+    let s = random_string(100);
+    let error = LocalFastStringN::<24, _>::try_from_str_in(&s, limited.clone()).unwrap_err();
+    assert!(matches!(error, TryReserveError::AllocError { .. }));
+    assert_eq!(
+        error.to_string(),
+        "memory allocation failed because the memory allocator returned an error"
+    );
+
+    let mut fs = LocalFastStringN::<24, _>::try_from_str_in(&s[..20], limited.clone()).unwrap();
+    assert!(fs.try_push_str(&s[20..30]).is_err());
+    assert!(is_same(&String::from(&s[..20]), &fs));
+    assert!(fs.try_push_str(&s[20..24]).is_ok());
+    assert!(fs.try_reserve(1).is_err());
+    assert!(is_same(&String::from(&s[..24]), &fs));
+
+    left.set(1);
+    assert!(fs.try_reserve(76).is_ok());
+    assert_eq!(left.get(), 0);
+    let ptr = fs.as_ptr();
+    assert!(fs.try_push_str(&s[24..]).is_ok());
+    assert_eq!(fs.as_ptr(), ptr);
+    assert!(is_same(&s, &fs));
+
+    let clone = fs.clone();
+    assert!(fs.try_push_str("!").is_err());
+    assert!(fs.try_reserve(0).is_ok());
+    assert!(is_same(&s, &fs) && is_same(&s, &clone));
+
+    let mut fs =
+        LocalFastStringN::<24, _>::from_static_in("static string longer than inline", limited);
+    assert!(fs.try_push_str("!").is_err());
+    assert_eq!(fs, "static string longer than inline");
+    assert_eq!(
+        fs.try_reserve(usize::MAX),
+        Err(TryReserveError::CapacityOverflow)
+    );
+    assert_eq!(
+        fs.try_reserve(FastString::MAX_LEN),
+        Err(TryReserveError::CapacityOverflow)
+    );
+
+    let mut fs = FastString::try_from_str(&s).unwrap();
+    assert!(fs.try_reserve(1000).is_ok());
+    assert!(fs.try_push_str(&s).is_ok());
+    let ptr = fs.as_ptr();
+    for _ in 0..9 {
+        assert!(fs.try_push_str(&s).is_ok());
+    }
+    assert_eq!(fs.as_ptr(), ptr);
+    assert_eq!(fs.len(), 1100);
+}
+
+#[test]
+fn test_slice() {
+    let s = random_string(100);
+    let fs = FastString::from(s.as_str());
+    let begin = fs.as_ptr();
+
+    let slice = fs.slice(30..80);
+    assert!(is_same(&String::from(&s[30..80]), &slice));
+    assert_eq!(slice.as_ptr(), begin.wrapping_add(30));
+    let short = fs.slice(..10);
+    assert!(is_same(&String::from(&s[..10]), &short));
+    assert!(is_inline(&short));
+    assert!(fs.slice(..).as_ptr() == begin);
+    assert!(fs.slice(100..).is_empty());
+
+    let (left, right) = slice.split_at(25);
+    assert!(left == s[30..55] && right == s[55..80]);
+    assert_eq!(right.as_ptr(), begin.wrapping_add(55));
+    let substr = slice.substr(&slice[10..]);
+    assert_eq!(substr, s[40..80]);
+    assert_eq!(substr.as_ptr(), begin.wrapping_add(40));
+
+    // The substring doesn't change the others and is copied before the first mutation
+    let mut mutable = slice.clone();
+    mutable.push_str("!");
+    mutable.remove(0);
+    let mut expected = String::from(&s[31..80]);
+    expected.push('!');
+    assert!(is_same(&expected, &mutable));
+    assert_ne!(mutable.as_ptr(), begin.wrapping_add(31));
+    assert!(is_same(&String::from(&s[30..80]), &slice));
+    drop(fs);
+
+    // The only owner of the buffer mutates it in place
+    let mut unique = slice.slice(10..);
+    drop((slice, left, right, substr));
+    let ptr = unique.as_ptr();
+    unique.remove(0);
+    assert_eq!(unique.as_ptr(), ptr);
+    assert!(is_same(&String::from(&s[41..80]), &unique));
+    unique.push('x');
+    let mut expected = String::from(&s[41..80]);
+    expected.push('x');
+    assert!(is_same(&expected, &unique));
+
+    let text = "static string longer than inline, split without allocation";
+    let fs = FastString::from_static(text);
+    let words: Vec<FastString> = text.split(' ').map(|word| fs.substr(word)).collect();
+    assert_eq!(words.len(), 8);
+    assert!(words.iter().zip(text.split(' ')).all(|(fs, s)| fs == s));
+    let long = fs.slice(7..);
+    assert_eq!(long.as_ptr(), text[7..].as_ptr());
+
+    let medium = FastString::from(random_string(100));
+    let slice = medium.slice(1..99);
+    assert!(slice == medium[1..99]);
+    assert!(slice.is_shared() && slice.as_ptr() == medium.as_ptr().wrapping_add(1));
+
+    // The tokens of the document read to String share its buffer
+    let text = (0..20)
+        .map(|_| random_string(40))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let doc = FastString::from(text.clone());
+    let tokens: Vec<FastString> = doc.split(' ').map(|token| doc.substr(token)).collect();
+    assert!(tokens.iter().zip(text.split(' ')).all(|(fs, s)| fs == s));
+    assert!(tokens.iter().all(|token| token.is_shared()));
+    assert!(tokens[1].as_ptr() == doc.as_ptr().wrapping_add(41));
+    assert_eq!(doc.strong_count(), 21);
+    drop(doc);
+    assert!(tokens.iter().zip(text.split(' ')).all(|(fs, s)| fs == s));
+
+    // split_off shares the buffer of the document with the tail
+    let mut doc = FastString::from(text.clone());
+    let tail = doc.split_off(100);
+    assert!(is_same(&String::from(&text[..100]), &doc));
+    assert!(is_same(&String::from(&text[100..]), &tail));
+    assert!(tail.is_shared() && tail.as_ptr() == doc.as_ptr().wrapping_add(100));
+}
+
+#[test]
+#[should_panic]
+fn test_substr_outside() {
+    let fs = FastString::from(random_string(100).as_str());
+    let other = random_string(10);
+    fs.substr(&other);
+}
+
+#[quickcheck]
+fn test_slice_prop(string: String, start: usize, end: usize) -> bool {
+    let mut fast_string = FastString::from(string.as_str());
+    let mut sliced = string.clone();
+    let old = slice_command(&mut sliced, &mut fast_string, start, end);
+    is_same(&sliced, &fast_string)
+        && old.is_none_or(|(old_s, old)| old_s == string && is_same(&string, &old))
+}
+
+#[test]
+fn test_intern() {
+    let interner = Interner::new();
+    let s = random_string(100);
+    let first = interner.intern(&s);
+    let second = interner.intern(&String::from(s.as_str()));
+    assert!(is_same(&s, &second));
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    let short = interner.intern("short");
+    assert_eq!(short, "short");
+
+    let symbol = interner.symbol(&s);
+    assert_eq!(interner.lookup(&s), Some(symbol));
+    assert_eq!(interner.lookup("missing"), None);
+    assert_eq!(interner.resolve(symbol).unwrap().as_ptr(), first.as_ptr());
+    let (other, string) = interner.intern_symbol("other");
+    assert_ne!(other, symbol);
+    assert_eq!(string, "other");
+    assert_eq!(interner.len(), 3);
+    assert_eq!(
+        interner.stats(),
+        Stats {
+            entries: 3,
+            hits: 2,
+            misses: 3,
+            purged: 0,
+        }
+    );
+
+    // The string is still used
+    let long = random_string(50);
+    let long_symbol = interner.symbol(&long);
+    assert_eq!(interner.purge(), 1);
+    assert_eq!(interner.resolve(long_symbol), None);
+    drop((first, second));
+    assert_eq!(interner.purge(), 1);
+    assert_eq!(interner.resolve(symbol), None);
+    assert_eq!(interner.lookup(&s), None);
+    assert_ne!(interner.symbol(&s), symbol);
+    assert_eq!(interner.resolve(other).unwrap(), "other");
+    assert_eq!(interner.stats().purged, 2);
+    assert_eq!(interner.stats().entries, 3);
+}
+
+#[test]
+fn test_intern_short() {
+    let interner = Interner::new();
+    let first = interner.intern("id");
+    let second = interner.intern("id");
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    assert_eq!(first.strong_count(), 4);
+    assert_eq!(interner.purge(), 0);
+    drop((first, second));
+    assert_eq!(interner.purge(), 1);
+    assert_eq!(interner.lookup("id"), None);
+    assert!(interner.is_empty());
+}
+
+#[test]
+fn test_intern_threads() {
+    let words: Vec<String> = (0..100).map(|i| random_string(i % 50 + 1)).collect();
+    let interned: Vec<Vec<FastString>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| words.iter().map(|word| intern::intern(word)).collect()))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    for strings in interned.iter() {
+        for ((fs, first), word) in strings.iter().zip(interned[0].iter()).zip(words.iter()) {
+            assert!(is_same(word, fs));
+            assert_eq!(fs.as_ptr(), first.as_ptr());
+        }
+    }
+    assert!(intern::global().len() <= words.len());
+}
+
+#[test]
+fn test_shrink() {
+    let s = random_string(100);
+    let mut fs = FastString::from(s.as_str());
+    fs.push_str(&s);
+    fs.shrink_to_fit();
+    let mut expected = s.clone();
+    expected.push_str(&s);
+    assert!(is_same(&expected, &fs));
+
+    // Shrinks the unique buffer in place and moves back inline
+    let counting = Counting::default();
+    let live = counting.0.clone();
+    let mut fs = LocalFastStringN::<24, _>::from_str_in(&s, counting);
+    let mut string = s.clone();
+    for _ in 0..80 {
+        assert!(remove_command(&mut string, &mut fs, 0));
+    }
+    assert_eq!(is_inline(&fs), cfg!(feature = "auto-demote"));
+    fs.shrink_to(24);
+    assert!(is_same(&string, &fs));
+    assert_eq!(live.get(), 0);
+    fs.push_str(&s[..10]);
+    string.push_str(&s[..10]);
+    fs.shrink_to(100);
+    assert!(is_same(&string, &fs));
+    assert_eq!(live.get(), 1);
+    fs.shrink_to_fit();
+    assert!(is_same(&string, &fs));
+    assert_eq!(live.get(), 1);
+
+    // The short slice of the shared buffer is copied, the full clone isn't
+    let fs = FastString::from(s.as_str());
+    let mut slice = fs.slice(10..40);
+    slice.shrink_to_fit();
+    assert!(slice == s[10..40]);
+    assert_ne!(slice.as_ptr(), fs.as_ptr().wrapping_add(10));
+    let mut clone = fs.clone();
+    clone.shrink_to_fit();
+    assert_eq!(clone.as_ptr(), fs.as_ptr());
+    let mut short = fs.slice(..20);
+    short.shrink_to_fit();
+    assert!(is_inline(&short));
+
+    // Medium is reallocated
+    let mut string = String::with_capacity(1000);
+    string.push_str(&s);
+    let mut fs = FastString::from(string);
+    fs.shrink_to(50);
+    assert!(is_same(&s, &fs));
+    assert_eq!(String::from(fs).capacity(), 100);
+    let mut fs = FastString::from(s.clone());
+    for _ in 0..80 {
+        fs.remove(0);
+    }
+    fs.shrink_to_fit();
+    assert!(is_inline(&fs));
+    assert!(fs == s[80..]);
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    fs.shrink_to_fit();
+    assert_eq!(fs, "static string longer than inline");
+}
+
+#[test]
+fn test_representation() {
+    let s = random_string(100);
+    let fs = FastString::from(&s[..10]);
+    assert_eq!(fs.representation(), Representation::Inline);
+    assert!(fs.is_inline() && !fs.is_shared());
+    assert_eq!(fs.strong_count(), 1);
+    assert_eq!(fs.capacity(), FastString::INLINE_CAPACITY);
+    let clone = fs.clone();
+    assert!(fs.ptr_eq(&fs));
+    assert!(!fs.ptr_eq(&clone));
+
+    let fs = FastString::from(s.as_str());
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert!(fs.capacity() >= 100);
+    let clone = fs.clone();
+    assert_eq!(fs.representation(), Representation::Shared);
+    assert!(fs.is_shared() && clone.is_shared());
+    assert_eq!(fs.strong_count(), 2);
+    assert!(fs.ptr_eq(&clone));
+    let slice = fs.slice(10..);
+    assert_eq!(fs.strong_count(), 3);
+    assert_eq!(slice.capacity(), fs.capacity() - 10);
+    assert!(!fs.ptr_eq(&slice));
+    drop((clone, slice));
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert!(!fs.ptr_eq(&FastString::from(s.as_str())));
+
+    let mut string = String::with_capacity(200);
+    string.push_str(&s);
+    let fs = FastString::from(string);
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert_eq!(fs.strong_count(), 1);
+    assert_eq!(fs.capacity(), 200);
+
+    let fs = FastString::from_static("static string longer than inline");
+    assert_eq!(fs.representation(), Representation::Static);
+    assert_eq!(fs.capacity(), fs.len());
+    assert!(fs.ptr_eq(&fs.clone()));
+    assert!(!fs.is_shared());
+}
+
+#[test]
+fn test_make_mut() {
+    let s = random_string(100);
+    let upper = s.to_ascii_uppercase();
+
+    let mut fs = FastString::from(&s[..10]);
+    fs.make_ascii_uppercase();
+    assert!(is_same(&upper[..10].to_string(), &fs));
+    assert!(fs.is_inline());
+
+    // The last byte of the full inline string is the tag
+    let mut fs = FastString::from(&s[..24]);
+    fs.make_mut().make_ascii_uppercase();
+    assert!(is_same(&upper[..24].to_string(), &fs));
+    assert_eq!(fs.representation(), Representation::Unique);
+
+    // The shared buffer is copied once, the clone isn't changed
+    let mut fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    fs.as_mut_str().make_ascii_uppercase();
+    assert!(is_same(&upper, &fs));
+    assert!(is_same(&s, &clone));
+    assert_eq!(fs.representation(), Representation::Unique);
+    let ptr = fs.as_ptr();
+    fs.make_ascii_lowercase();
+    assert_eq!(fs.as_ptr(), ptr);
+    assert!(is_same(&s.to_ascii_lowercase(), &fs));
+
+    let mut slice = clone.slice(10..60);
+    slice.make_ascii_uppercase();
+    assert!(is_same(&upper[10..60].to_string(), &slice));
+    assert!(is_same(&s, &clone));
+
+    // Medium is changed in place
+    let mut fs = FastString::from(s.clone());
+    let ptr = fs.as_ptr();
+    fs.make_ascii_uppercase();
+    assert_eq!(fs.as_ptr(), ptr);
+    assert_eq!(String::from(fs), upper);
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    fs.make_ascii_uppercase();
+    assert_eq!(fs, "STATIC STRING LONGER THAN INLINE");
+    assert_eq!(fs.representation(), Representation::Unique);
+}
+
+#[test]
+fn test_weak() {
+    let s = random_string(100);
+    let counting = Counting::default();
+    let live = counting.0.clone();
+    let fs = LocalFastStringN::<24, _>::from_str_in(&s, counting);
+    let weak = fs.downgrade();
+    assert_eq!(weak.strong_count(), 1);
+    let upgraded = weak.upgrade().unwrap();
+    assert!(is_same(&s, &upgraded));
+    assert!(upgraded.ptr_eq(&fs));
+    assert_eq!(fs.strong_count(), 2);
+    drop(upgraded);
+
+    // The weak reference blocks mutation in place, the buffer is copied
+    let mut fs = fs;
+    let ptr = fs.as_ptr();
+    fs.push('!');
+    assert_ne!(fs.as_ptr(), ptr);
+    assert_eq!(live.get(), 2);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(weak.strong_count(), 0);
+    let clone = weak.clone();
+    drop(weak);
+    assert_eq!(live.get(), 2);
+    drop(clone);
+    assert_eq!(live.get(), 1);
+
+    // Shared only while a weak reference is alive, then mutated in place
+    assert_eq!(fs.representation(), Representation::Unique);
+    let weak = fs.downgrade();
+    assert!(fs.is_shared());
+    assert_eq!(fs.strong_count(), 1);
+    drop(weak);
+    assert!(!fs.is_shared());
+    let ptr = fs.as_ptr();
+    fs.make_ascii_uppercase();
+    assert_eq!(fs.as_ptr(), ptr);
+
+    // The substring upgrades to itself
+    let slice = fs.slice(10..60);
+    let weak = slice.downgrade();
+    drop(fs);
+    assert!(weak.upgrade().unwrap().ptr_eq(&slice));
+    drop(slice);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(live.get(), 1);
+    drop(weak);
+    assert_eq!(live.get(), 0);
+
+    // The buffer adopted from String is shared by downgrade
+    let fs = FastString::from(s.clone());
+    let ptr = fs.as_ptr();
+    let weak = fs.downgrade();
+    assert_eq!(fs.as_ptr(), ptr);
+    assert!(fs.is_shared());
+    let upgraded = weak.upgrade().unwrap();
+    assert!(upgraded.ptr_eq(&fs));
+    assert_eq!(weak.strong_count(), 2);
+    drop(upgraded);
+    drop(fs);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(weak.strong_count(), 0);
+    let slice = FastString::from(s.clone()).slice(10..60);
+    let weak = slice.downgrade();
+    assert!(weak.upgrade().unwrap().ptr_eq(&slice));
+    drop(slice);
+    assert!(weak.upgrade().is_none());
+
+    // Other strings are copied
+    let fs = FastString::from(&s[..10]);
+    let weak = fs.downgrade();
+    drop(fs);
+    assert_eq!(weak.upgrade().unwrap(), s[..10]);
+    assert_eq!(weak.strong_count(), 1);
+    let weak = FastString::from_static("static string longer than inline").downgrade();
+    assert_eq!(weak.upgrade().unwrap(), "static string longer than inline");
+    assert_eq!(format!("{:?}", weak), "(Weak)");
+}
+
+#[test]
+fn test_weak_threads() {
+    let s = random_string(100);
+    for i in 0..100 {
+        // The shared buffer or the one adopted from String
+        let fs = if i % 2 == 0 {
+            FastString::from(s.as_str())
+        } else {
+            FastString::from(s.clone())
+        };
+        let weak = fs.downgrade();
+        let mut clone = fs.clone();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    if let Some(upgraded) = weak.upgrade() {
+                        assert!(is_same(&s, &upgraded));
+                    }
+                }
+                drop(weak);
+            });
+            scope.spawn(move || drop(fs));
+            // Mutates in place only if nothing can upgrade
+            for _ in 0..10 {
+                clone.make_ascii_uppercase();
+                clone.make_ascii_lowercase();
+            }
+        });
+        assert!(is_same(&s.to_ascii_lowercase(), &clone));
+    }
+}
+
+#[test]
+fn test_hash_code() {
+    let s = random_string(100);
+    let hash_code = |text: &str| FastString::from_string_in(text.to_string(), Global).hash_code();
+    let mut fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    assert_eq!(fs.hash_code(), hash_code(&s));
+    assert_eq!(clone.hash_code(), hash_code(&s));
+    assert_eq!(fs.slice(..50).hash_code(), hash_code(&s[..50]));
+    assert_eq!(fs.slice(10..).hash_code(), hash_code(&s[10..]));
+    assert_eq!(fs.slice(..10).hash_code(), hash_code(&s[..10]));
+    let fs_static = FastString::from_static("static string longer than inline");
+    assert_eq!(
+        fs_static.hash_code(),
+        hash_code("static string longer than inline")
+    );
+    // The String buffer shared by clones
+    let adopted = FastString::from(s.clone());
+    let adopted_clone = adopted.clone();
+    assert_eq!(adopted.hash_code(), hash_code(&s));
+    assert_eq!(adopted_clone.hash_code(), hash_code(&s));
+    assert_eq!(adopted.slice(..50).hash_code(), hash_code(&s[..50]));
+    // The empty text of the heap buffer
+    let empty = FastString::new().hash_code();
+    assert_eq!(FastString::with_capacity(100).hash_code(), empty);
+    let mut cleared = FastString::from(s.as_str());
+    assert_eq!(cleared.hash_code(), hash_code(&s));
+    cleared.clear();
+    assert_eq!(cleared.hash_code(), empty);
+    assert_eq!(hash_code(""), empty);
+
+    // Mutation without change of the len resets the hash
+    drop(clone);
+    fs.make_ascii_uppercase();
+    assert_eq!(fs.hash_code(), hash_code(&s.to_ascii_uppercase()));
+    fs.remove(0);
+    fs.push('!');
+    let mut expected = s.to_ascii_uppercase()[1..].to_string();
+    expected.push('!');
+    assert_eq!(fs.hash_code(), hash_code(&expected));
+
+    let mut map = std::collections::HashMap::new();
+    map.insert(CachedHash(fs.clone()), 1);
+    map.insert(CachedHash(FastString::from(&s[..10])), 2);
+    assert_eq!(
+        map.get(&CachedHash(FastString::from(expected.as_str()))),
+        Some(&1)
+    );
+    assert_eq!(map.get(&CachedHash(fs)), Some(&1));
+    assert_eq!(map.get(&CachedHash(FastString::from(&s[..10]))), Some(&2));
+    assert_eq!(map.get(&CachedHash(FastString::from(s.as_str()))), None);
+}
+
+#[test]
+fn test_compare() {
+    let s = random_string(100);
+    let fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    assert_eq!(fs, clone);
+    assert_eq!(fs.cmp(&clone), std::cmp::Ordering::Equal);
+    // The same text pointer with other len
+    let prefix = fs.slice(..60);
+    assert_ne!(fs, prefix);
+    assert!(prefix < fs);
+    assert!(fs > prefix);
+
+    // Inline strings of other capacity, the bytes after len differ
+    let mut short = FastString::from(&s[..20]);
+    short.remove(19);
+    let other: FastStringN<32> = FastStringN::from(&s[..19]);
+    assert!(short == other);
+    assert_eq!(short.partial_cmp(&other), Some(std::cmp::Ordering::Equal));
+    let ab = FastString::from("ab");
+    let abc = FastString::from("abc");
+    let b = FastString::from("b");
+    assert!(ab < abc && abc < b && ab < b);
+    let full = FastString::from("abcdefghijklmnopqrstuvwx");
+    let longer = FastString::from("abcdefghijklmnopqrstuvwxy");
+    assert!(full < longer);
+    assert_ne!(full, longer);
+}
+
+#[quickcheck]
+fn test_compare_prop(string: String, other: String) -> bool {
+    let fs = FastString::from(string.as_str());
+    let fs_other: FastStringN<32> = FastStringN::from(other.as_str());
+    let fs_same = FastString::from(other.as_str());
+    let expected = string.cmp(&other);
+    (fs == fs_other) == (string == other)
+        && fs.partial_cmp(&fs_other) == Some(expected)
+        && fs.cmp(&fs_same) == expected
+        && fs_same.cmp(&fs) == expected.reverse()
+        && fs == fs.clone()
+}
+
+#[test]
+fn test_with_capacity() {
+    let s = random_string(100);
+    let fs = FastString::with_capacity(10);
+    assert!(fs.is_inline() && fs.is_empty());
+    assert_eq!(fs.capacity(), 24);
+
+    let mut fs = FastString::with_capacity(100);
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert!(fs.capacity() >= 100 && fs.is_empty());
+    let ptr = fs.as_ptr();
+    fs.push_str(&s);
+    assert_eq!(fs.as_ptr(), ptr);
+    assert!(is_same(&s, &fs));
+
+    // The shared buffer is copied, the clone isn't changed
+    let clone = fs.clone();
+    fs.reserve(100);
+    assert!(fs.capacity() >= 200);
+    assert_ne!(fs.as_ptr(), clone.as_ptr());
+    let ptr = fs.as_ptr();
+    fs.push_str(&s);
+    assert_eq!(fs.as_ptr(), ptr);
+    assert!(is_same(&s, &clone));
+
+    // Amortized and exact growth of the String buffer
+    let mut fs = FastString::from(s.clone());
+    fs.reserve_exact(10);
+    assert_eq!(fs.capacity(), 110);
+    fs.reserve(20);
+    assert_eq!(fs.capacity(), 220);
+    fs.reserve(0);
+    fs.reserve_exact(10);
+    assert_eq!(fs.capacity(), 220);
+    assert!(is_same(&s, &fs));
+
+    let mut fs = FastString::from(&s[..10]);
+    fs.reserve(14);
+    assert!(fs.is_inline());
+    fs.reserve_exact(15);
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert!(fs.capacity() >= 25);
+    assert!(is_same(&s[..10].to_string(), &fs));
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    fs.reserve(1);
+    assert_eq!(fs.representation(), Representation::Unique);
+    assert_eq!(fs, "static string longer than inline");
+}
+
+#[test]
+fn test_insert() {
+    let s = random_string(100);
+    let mut string = String::from(&s[..10]);
+    let mut fs = FastString::from(string.as_str());
+    for (idx, ch) in [(0, 'a'), (5, 'ж'), (12, '!')] {
+        string.insert(idx, ch);
+        fs.insert(idx, ch);
+        assert!(is_same(&string, &fs));
+    }
+    assert!(fs.is_inline());
+    // Moves to the heap in one copy
+    assert!(insert_str_command(&mut string, &mut fs, 3, &s[..20]));
+    assert_eq!(fs.representation(), Representation::Unique);
+
+    // The unique buffer is shifted in place
+    fs.reserve(10);
+    let ptr = fs.as_ptr();
+    assert!(insert_str_command(&mut string, &mut fs, 0, "0123456789"));
+    assert_eq!(fs.as_ptr(), ptr);
+
+    // The shared buffer is copied, the clone isn't changed
+    let clone = fs.clone();
+    let old = string.clone();
+    assert!(insert_str_command(&mut string, &mut fs, 7, "shared"));
+    assert_ne!(fs.as_ptr(), clone.as_ptr());
+    assert!(is_same(&old, &clone));
+    let mut slice = clone.slice(10..);
+    let mut tail = old[10..].to_string();
+    assert!(insert_str_command(&mut tail, &mut slice, 4, "slice"));
+    assert!(is_same(&old, &clone));
+
+    let mut medium = FastString::from(s.clone());
+    let mut string = s.clone();
+    assert!(insert_str_command(&mut string, &mut medium, 50, &s));
+    assert_eq!(String::from(medium), string);
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    fs.insert_str(6, "ally allocated");
+    assert_eq!(fs, "statically allocated string longer than inline");
+}
+
+#[test]
+#[should_panic(expected = "is_char_boundary")]
+fn test_insert_boundary() {
+    let mut fs = FastString::from("жук");
+    fs.insert(1, 'a');
+}
+
+#[test]
+fn test_truncate() {
+    let s = random_string(100);
+    let mut fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    // The shared buffer isn't copied
+    fs.truncate(60);
+    assert!(is_same(&s[..60].to_string(), &fs));
+    assert_eq!(fs.as_ptr(), clone.as_ptr());
+    assert!(is_same(&s, &clone));
+    fs.truncate(80);
+    assert_eq!(fs.len(), 60);
+    fs.push('!');
+    assert_ne!(fs.as_ptr(), clone.as_ptr());
+    assert!(is_same(&s, &clone));
+
+    let mut fs = clone.clone();
+    let tail = fs.split_off(30);
+    assert!(is_same(&s[..30].to_string(), &fs));
+    assert!(is_same(&s[30..].to_string(), &tail));
+    assert_eq!(tail.as_ptr(), clone.as_ptr().wrapping_add(30));
+    let short = fs.split_off(20);
+    assert!(short.is_inline());
+    assert_eq!(fs.is_inline(), cfg!(feature = "auto-demote"));
+
+    // The own buffer keeps its capacity
+    let mut fs = FastString::from(s.as_str());
+    let capacity = fs.capacity();
+    fs.clear();
+    assert!(fs.is_empty());
+    assert_eq!(fs.is_inline(), cfg!(feature = "auto-demote"));
+    if !fs.is_inline() {
+        assert_eq!(fs.capacity(), capacity);
+    }
+    let count = clone.strong_count();
+    let mut fs = clone.clone();
+    fs.clear();
+    assert!(fs.is_inline() && fs.is_empty());
+    assert_eq!(clone.strong_count(), count);
+
+    let mut fs = FastString::from("жук");
+    assert_eq!(fs.pop(), Some('к'));
+    assert_eq!(fs.pop(), Some('у'));
+    assert_eq!(fs.pop(), Some('ж'));
+    assert_eq!(fs.pop(), None);
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    assert_eq!(fs.split_off(13), " longer than inline");
+    assert_eq!(fs, "static string");
+    fs.clear();
+    assert!(fs.is_inline() && fs.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "is_char_boundary")]
+fn test_truncate_boundary() {
+    let mut fs = FastString::from("жук");
+    fs.truncate(1);
+}
+
+#[test]
+fn test_replace_range() {
+    let s = random_string(100);
+    let mut string = s.clone();
+    let mut fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    // The prefix and the suffix of the shared buffer are removed without copy
+    assert!(replace_range_command(&mut string, &mut fs, 0, 10, ""));
+    assert_eq!(fs.as_ptr(), clone.as_ptr().wrapping_add(10));
+    assert!(replace_range_command(&mut string, &mut fs, 80, 90, ""));
+    assert_eq!(fs.as_ptr(), clone.as_ptr().wrapping_add(10));
+    // The middle is copied once, then changed in place
+    assert!(replace_range_command(
+        &mut string,
+        &mut fs,
+        10,
+        20,
+        "middle"
+    ));
+    assert_ne!(fs.as_ptr(), clone.as_ptr().wrapping_add(10));
+    assert!(is_same(&s, &clone));
+    let ptr = fs.as_ptr();
+    assert!(replace_range_command(&mut string, &mut fs, 0, 5, "new"));
+    assert!(replace_range_command(&mut string, &mut fs, 30, 40, ""));
+    assert_eq!(fs.as_ptr(), ptr);
+    // The short result of the shared buffer is inline
+    let mut short = clone.clone();
+    short.replace_range(10.., "end");
+    assert!(short.is_inline());
+    assert_eq!(short, format!("{}end", &s[..10]));
+
+    let mut fs = FastString::from("жук и паук");
+    let mut drain = fs.drain(..7);
+    assert_eq!(drain.next(), Some('ж'));
+    assert_eq!(drain.next_back(), Some(' '));
+    assert_eq!(drain.as_str(), "ук");
+    assert_eq!(format!("{:?}", drain), "Drain(\"ук\")");
+    drop(drain);
+    assert_eq!(fs, "и паук");
+    fs.drain(2..=2);
+    assert_eq!(fs, "ипаук");
+
+    let mut fs = FastString::from_static("static string longer than inline");
+    fs.drain(..7);
+    assert_eq!(fs, "string longer than inline");
+    fs.replace_range(..6, "text");
+    assert_eq!(fs, "text longer than inline");
+}
+
+#[test]
+fn test_retain() {
+    let s = random_string(100);
+    let mut fs = FastString::from(s.as_str());
+    let clone = fs.clone();
+    fs.retain(|ch| ch.is_ascii_lowercase());
+    let expected: String = s.chars().filter(|ch| ch.is_ascii_lowercase()).collect();
+    assert!(is_same(&expected, &fs));
+    assert!(is_same(&s, &clone));
+
+    // The full inline string keeps its last byte
+    let text = &s[..24];
+    let mut fs = FastString::from(text);
+    fs.retain(|_| true);
+    assert!(is_same(&text.to_string(), &fs));
+    fs.retain(|ch| ch != text.chars().next().unwrap());
+    let expected: String = text
+        .chars()
+        .filter(|ch| *ch != text.chars().next().unwrap())
+        .collect();
+    assert!(is_same(&expected, &fs));
+
+    // The processed part is kept after panic
+    let mut fs = FastString::from("a1b2c3");
+    let mut count = 0;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        fs.retain(|ch| {
+            count += 1;
+            if count == 4 {
+                panic!("retain");
+            }
+            ch.is_alphabetic()
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(fs, "ab");
+}
+
+#[quickcheck]
+fn test_replace_range_prop(mut string: String, start: usize, end: usize, add: String) -> bool {
+    let mut fast_string = FastString::from(string.as_str());
+    let clone = fast_string.clone();
+    let old = string.clone();
+    replace_range_command(&mut string, &mut fast_string, start, end, add.as_str())
+        && is_same(&old, &clone)
+}
+
+#[test]
+fn test_extend() {
+    let s = random_string(100);
+    let fs: FastString = s.chars().collect();
+    assert!(is_same(&s, &fs));
+    let fs: FastString = s[..10].chars().collect();
+    assert!(fs.is_inline());
+    let fs: FastString = s.split(' ').collect();
+    assert_eq!(fs, s.replace(' ', ""));
+    let fs: FastString = ["short", "words"].iter().copied().collect();
+    assert!(fs.is_inline());
+    let fs: FastString = vec![s.clone(), s.clone()].into_iter().collect();
+    assert_eq!(fs, s.clone() + &s);
+    let parts: Vec<FastStringN<32>> =
+        vec![FastStringN::from(&s[..50]), FastStringN::from(&s[50..])];
+    let fs: FastString = parts.into_iter().collect();
+    assert!(is_same(&s, &fs));
+    let empty: FastString = Vec::<String>::new().into_iter().collect();
+    assert!(empty.is_empty() && empty.is_inline());
+
+    // The size hint of chars reserves once
+    let mut fs = FastString::from(&s[..10]);
+    fs.extend(s[10..].chars());
+    assert!(is_same(&s, &fs));
+    let mut fs = FastString::with_capacity(0);
+    fs.extend(['a'; 100].iter().copied());
+    let ptr = fs.as_ptr();
+    assert!(fs.capacity() >= 100);
+    fs.extend(vec!["b"; 10]);
+    fs.extend(vec![String::from("c")]);
+    let clone: FastString = fs.clone();
+    fs.extend(vec![clone.clone()]);
+    let expected = "a".repeat(100) + &"b".repeat(10) + "c";
+    assert_eq!(fs, expected.repeat(2));
+    assert_ne!(fs.as_ptr(), ptr);
+
+    // The size hint reserves a byte per string, then the capacity doubles:
+    // from 50 to 275 bytes it grows a few times, not once per string
+    let left = Rc::new(Cell::new(1));
+    let mut fs = LocalFastStringN::<24, _>::new_in(Limited(left.clone()));
+    fs.extend(vec!["b"; 100]);
+    assert_eq!(fs, "b".repeat(100));
+    assert_eq!(left.get(), 0);
+    let words: Vec<String> = (0..50).map(|i| random_string(i % 10 + 1)).collect();
+    let expected = words.concat();
+    let growths = |extend: &dyn Fn(&mut LocalFastStringN<24, Limited>)| {
+        let left = Rc::new(Cell::new(usize::MAX));
+        let mut fs = LocalFastStringN::<24, _>::new_in(Limited(left.clone()));
+        extend(&mut fs);
+        assert!(is_same(&expected, &fs));
+        usize::MAX - left.get()
+    };
+    assert!(growths(&|fs| fs.extend(words.iter().map(String::as_str))) <= 5);
+    assert!(growths(&|fs| fs.extend(words.clone())) <= 5);
+    assert!(
+        growths(&|fs| fs.extend(words.iter().map(String::as_str).map(LocalFastString::from))) <= 5
+    );
+
+    let fs = FastString::from("short") + " string";
+    assert!(fs.is_inline());
+    assert_eq!(fs, "short string");
+    let mut fs = fs + &s;
+    fs += "!";
+    assert_eq!(fs, format!("short string{}!", s));
+}